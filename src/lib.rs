@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+pub mod metainfo;
+pub mod peers;
+pub mod storage;
@@ -1,10 +1,9 @@
 use std::env;
-use sha1::{Digest, Sha1};
-use crate::metainfo::{Parser, TorrentMetaInfo};
-use crate::peers::Peers;
-
-mod metainfo;
-mod peers;
+use std::net::SocketAddr;
+use std::path::Path;
+use rusty_bittorrent::metainfo::{FileLayout, Magnet, Parser, TorrentBuilder, TorrentMetaInfo};
+use rusty_bittorrent::peers::{fetch_metadata, Peers, DEFAULT_PORT};
+use rusty_bittorrent::storage::{verify, FileStorage, PieceStatus};
 
 fn parse_torrent_file(torrent_file_path : &String) -> TorrentMetaInfo {
     let parser = Parser::new(torrent_file_path.clone());
@@ -14,8 +13,24 @@ fn parse_torrent_file(torrent_file_path : &String) -> TorrentMetaInfo {
     }
 }
 
+// Torrents can be given either as a .torrent file or as a magnet link, whose metadata is fetched
+// telling peers we listen on `listen_port`
+async fn load_torrent(torrent_source : &String, listen_port : u16) -> TorrentMetaInfo {
+    if !torrent_source.starts_with("magnet:") {
+        return parse_torrent_file(torrent_source);
+    }
+    let magnet = match Magnet::parse(torrent_source) {
+        Ok(magnet) => magnet,
+        Err(_) => panic!("Invalid magnet link: {}", torrent_source)
+    };
+    match fetch_metadata(&magnet, listen_port).await {
+        Ok(metainfo) => metainfo,
+        Err(err) => panic!("Could not fetch metadata for {}: {}", torrent_source, err)
+    }
+}
+
 fn custom_assert(result: bool, panic_message: &str) {
-    if result == false {
+    if !result {
         panic!("{}", panic_message);
     }
 }
@@ -24,44 +39,112 @@ fn custom_assert(result: bool, panic_message: &str) {
 async fn main() {
     let args: Vec<String> = env::args().collect();
     if args[1].to_lowercase() == "info" {
-        custom_assert(args.len() == 3, "usage: info [TORRENT_FILE_PATH]");
+        custom_assert(args.len() == 3, "usage: info [TORRENT_FILE_PATH|MAGNET_LINK]");
         let torrent_file_path = args[2].clone();
-        let metainfo = parse_torrent_file(&torrent_file_path);
+        let metainfo = load_torrent(&torrent_file_path, DEFAULT_PORT).await;
         print!("{}", metainfo);
     } else if args[1].to_lowercase() == "peers" {
-        custom_assert(args.len() == 3, "usage: peers [TORRENT_FILE_PATH]");
+        custom_assert(args.len() == 3, "usage: peers [TORRENT_FILE_PATH|MAGNET_LINK]");
         let torrent_file_path = args[2].clone();
-        let metainfo = parse_torrent_file(&torrent_file_path);
-        let peers = Peers::new(metainfo).discover().await.unwrap();
-        println!("{}", peers);
+        let metainfo = load_torrent(&torrent_file_path, DEFAULT_PORT).await;
+        match Peers::new(metainfo).discover().await {
+            Ok(peers) => println!("{}", peers),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    } else if args[1].to_lowercase() == "scrape" {
+        custom_assert(args.len() == 3, "usage: scrape [TORRENT_FILE_PATH|MAGNET_LINK]");
+        let torrent_file_path = args[2].clone();
+        let metainfo = load_torrent(&torrent_file_path, DEFAULT_PORT).await;
+        for (tracker, result) in Peers::new(metainfo).scrape().await {
+            println!("Tracker: {}", tracker);
+            match result {
+                Ok(scrape_file) => println!("{}", scrape_file),
+                Err(err) => println!("Error: {}", err)
+            }
+        }
     } else if args[1].to_lowercase() == "handshake" {
-        custom_assert(args.len() == 4, "usage: handshake [TORRENT_FILE_PATH] PEER_IP:PEER_PORT");
+        custom_assert(args.len() == 4, "usage: handshake [TORRENT_FILE_PATH|MAGNET_LINK] PEER_IP:PEER_PORT");
         let torrent_file_path = args[2].clone();
-        let peer_address = args[3].clone();
-        let metainfo = parse_torrent_file(&torrent_file_path);
+        let peer_address : SocketAddr = args[3].parse().expect("peer address is not a valid IP:PORT");
+        let metainfo = load_torrent(&torrent_file_path, DEFAULT_PORT).await;
         let mut peers = Peers::new(metainfo);
-        let handshake = peers.handshake(&peer_address).unwrap();
+        let handshake = peers.handshake(peer_address).await.unwrap();
         println!("Peer ID: {}", base16ct::lower::encode_string(&handshake.peer_id));
         println!("Info hash: {}", base16ct::lower::encode_string(&handshake.info_hash));
     } else if args[1].to_lowercase() == "download_piece" {
-        custom_assert(args.len() == 4, "usage: download_piece [TORRENT_FILE_PATH] PIECE_INDEX");
+        custom_assert(args.len() == 4, "usage: download_piece [TORRENT_FILE_PATH|MAGNET_LINK] PIECE_INDEX");
         let torrent_file_path = args[2].clone();
         let piece_index : usize = args[3].parse::<usize>().expect("piece index is not a valid number");
-        let metainfo = parse_torrent_file(&torrent_file_path);
+        let metainfo = load_torrent(&torrent_file_path, DEFAULT_PORT).await;
         let mut peers = Peers::new(metainfo);
         let tracker_response = peers.discover().await.unwrap();
         let peers_ips = tracker_response.peers();
-        let piece = peers.download_piece(&peers_ips[0], piece_index).expect(format!("failed to download piece {piece_index}").as_str());
-        let hash = Sha1::digest(&piece);
-        assert_eq!(base16ct::lower::encode_string(&hash), peers.pieces_hash[piece_index]);
+        let piece = peers.download_piece(peers_ips[0], piece_index).await
+            .unwrap_or_else(|_| panic!("failed to download piece {piece_index}"));
+        if !peers.check_piece(piece_index, &piece) {
+            eprintln!("Piece #{piece_index} failed the hash check");
+            std::process::exit(1);
+        }
         println!("Downloaded piece#{}={} bytes", piece_index, piece.len());
     } else if args[1].to_lowercase() == "download" {
-        custom_assert(args.len() == 3, "usage: download [TORRENT_FILE_PATH]");
+        custom_assert(args.len() == 3 || args.len() == 4, "usage: download [TORRENT_FILE_PATH|MAGNET_LINK] [LISTEN_PORT]");
         let torrent_file_path = args[2].clone();
-        let metainfo = parse_torrent_file(&torrent_file_path);
+        let listen_port = args.get(3).map(|listen_port| listen_port.parse::<u16>().expect("listen port is not a valid port number"));
+        let metainfo = load_torrent(&torrent_file_path, listen_port.unwrap_or(DEFAULT_PORT)).await;
         let mut peers = Peers::new(metainfo);
-        let tracker_response = peers.discover().await.unwrap();
-        let peers_ips = tracker_response.peers();
-        peers.download(&peers_ips[0]).unwrap();
+        if let Some(listen_port) = listen_port {
+            peers.set_listen_port(listen_port);
+        }
+        // Interrupting saves the progress for the next run, once complete we keep seeding until then
+        let stop = peers.stop_token();
+        tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                let _ = tokio::signal::ctrl_c().await;
+                stop.cancel();
+            }
+        });
+        if let Err(err) = peers.download_torrent(stop.cancelled_owned()).await {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    } else if args[1].to_lowercase() == "verify" {
+        custom_assert(args.len() == 3 || args.len() == 4, "usage: verify [TORRENT_FILE_PATH|MAGNET_LINK] [DATA_DIR]");
+        let torrent_file_path = args[2].clone();
+        let data_directory = Path::new(args.get(3).map_or(".", String::as_str));
+        let metainfo = load_torrent(&torrent_file_path, DEFAULT_PORT).await;
+        let layout = FileLayout::new(&metainfo.info).unwrap_or_else(|err| panic!("Invalid torrent {torrent_file_path}: {err}"));
+        let pieces_hash = Peers::new(metainfo).pieces_hash;
+        // Only read, files that aren't there are reported missing instead of being created
+        let report = verify(&FileStorage::open(layout, data_directory), &pieces_hash);
+        print!("{}", report);
+        if report.pieces.iter().any(|status| *status != PieceStatus::Valid) {
+            std::process::exit(1);
+        }
+    } else if args[1].to_lowercase() == "create" {
+        let usage = "usage: create [FILE|DIRECTORY] OUTPUT_PATH TRACKER_URL... [--piece-length N] [--comment TEXT] [--web-seed URL] [--private]";
+        custom_assert(args.len() >= 5, usage);
+        let mut builder = TorrentBuilder::new(Path::new(&args[2]));
+        let mut options = args[4..].iter();
+        while let Some(option) = options.next() {
+            builder = match option.as_str() {
+                "--piece-length" => builder.with_piece_length(options.next().and_then(|length| length.parse().ok())
+                    .expect("piece length is not a valid number")),
+                "--comment" => builder.with_comment(options.next().expect(usage)),
+                "--web-seed" => builder.with_web_seed(options.next().expect(usage)),
+                "--private" => builder.with_private(true),
+                option if option.starts_with("--") => panic!("unknown option {option}\n{usage}"),
+                tracker => builder.with_tracker(tracker)
+            };
+        }
+        let metainfo = builder.build().unwrap_or_else(|err| {
+            eprintln!("{err}");
+            std::process::exit(1);
+        });
+        metainfo.save(Path::new(&args[3])).unwrap_or_else(|err| panic!("Could not write {}: {}", args[3], err));
+        println!("Created {} with {} pieces, info hash {}", args[3], metainfo.info.pieces.len() / 20, metainfo.info.hash_base16());
     }
 }
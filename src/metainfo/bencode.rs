@@ -0,0 +1,129 @@
+// Finds where values sit in bencoded data without decoding them, for when the exact bytes matter:
+// the info hash is computed over the info dictionary as it was encoded, keys we don't model included.
+// https://wiki.theory.org/BitTorrentSpecification#Bencoding
+
+// The encoded value of `key` in the dictionary `data` starts with
+pub fn dictionary_value<'a>(data : &'a [u8], key : &[u8]) -> Option<&'a [u8]> {
+    let (start, end) = dictionary_value_span(data, key)?;
+    Some(&data[start..end])
+}
+
+// Start and end offsets of the encoded value of `key` in the dictionary `data` starts with
+pub fn dictionary_value_span(data : &[u8], key : &[u8]) -> Option<(usize, usize)> {
+    if data.first() != Some(&b'd') {
+        return None;
+    }
+    let mut position = 1;
+    while data.get(position)? != &b'e' {
+        let key_end = value_end(data, position)?;
+        let value_start = key_end;
+        let value_end = value_end(data, value_start)?;
+        if string_content(data, position) == Some(key) {
+            return Some((value_start, value_end));
+        }
+        position = value_end;
+    }
+    None
+}
+
+// Offset right after the value starting at `start`
+fn value_end(data : &[u8], start : usize) -> Option<usize> {
+    start.checked_add(bencoded_value_length(data.get(start..)?)?)
+}
+
+// Length of the bencoded value at the start of `bytes`, also used when raw data follows a bencoded dictionary.
+// Walks nested lists and dictionaries without recursing, however deep a peer nests them.
+pub fn bencoded_value_length(bytes : &[u8]) -> Option<usize> {
+    let mut offset = 0usize;
+    let mut depth = 0usize;
+    loop {
+        match *bytes.get(offset)? {
+            b'i' => offset += bytes[offset..].iter().position(|&byte| byte == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                offset += 1;
+                continue;
+            },
+            b'e' if depth > 0 => {
+                depth -= 1;
+                offset += 1;
+            },
+            b'0'..=b'9' => {
+                let colon = offset + bytes[offset..].iter().position(|&byte| byte == b':')?;
+                let length : usize = std::str::from_utf8(&bytes[offset..colon]).ok()?.parse().ok()?;
+                let end = colon.checked_add(1)?.checked_add(length)?;
+                if end > bytes.len() {
+                    return None;
+                }
+                offset = end;
+            },
+            _ => return None
+        }
+        if depth == 0 {
+            return Some(offset);
+        }
+    }
+}
+
+// Content of the string starting at `start`
+fn string_content(data : &[u8], start : usize) -> Option<&[u8]> {
+    let colon = start + data[start..].iter().position(|&byte| byte == b':')?;
+    data.get(colon + 1..value_end(data, start)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn value_length_spans_nested_values() {
+        for (bytes, length) in [
+            (&b"i42e"[..], 4),
+            (b"4:spam", 6),
+            (b"le", 2),
+            (b"l4:spami-3ee", 12),
+            (b"d3:cowl3:moo4:oinkee", 20),
+            (b"ld1:ald1:bleeeeei1e", 16),
+            // Whatever follows the value is not part of it
+            (b"d1:ai1eeraw piece data", 8)
+        ] {
+            assert_eq!(bencoded_value_length(bytes), Some(length), "{}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn value_length_skips_delimiters_inside_strings() {
+        assert_eq!(bencoded_value_length(b"5:a:b:e"), Some(7));
+        assert_eq!(bencoded_value_length(b"l3:eeee"), Some(7));
+        assert_eq!(bencoded_value_length(b"d1:e2:d:e"), Some(9));
+    }
+
+    #[test]
+    fn value_length_rejects_truncated_input() {
+        for bytes in [&b""[..], b"i42", b"4:spa", b"4", b"l4:spam", b"d3:cowl3:mooe", b"ld1:ald1:blee", b"x", b"e", b"-1:a"] {
+            assert_eq!(bencoded_value_length(bytes), None, "{}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn dictionary_value_finds_top_level_keys() {
+        let data = b"d1:ad1:bi1ee1:b3:e:d1:cli2eee";
+        assert_eq!(dictionary_value(data, b"a"), Some(&b"d1:bi1ee"[..]));
+        // Only keys of the outer dictionary count, not the one nested in `a`
+        assert_eq!(dictionary_value(data, b"b"), Some(&b"3:e:d"[..]));
+        assert_eq!(dictionary_value(data, b"c"), Some(&b"li2ee"[..]));
+        assert_eq!(dictionary_value(data, b"d"), None);
+        assert_eq!(dictionary_value(b"l1:ae", b"a"), None);
+        assert_eq!(dictionary_value(b"d1:ai1e1:b", b"b"), None);
+    }
+
+    #[test]
+    fn info_span_hashes_to_the_info_hash() {
+        let torrent = include_bytes!("../../sample.torrent");
+        let (start, end) = dictionary_value_span(torrent, b"info").unwrap();
+        assert_eq!(torrent[start], b'd');
+        assert_eq!(end, torrent.len() - 1);
+        assert_eq!(base16ct::lower::encode_string(&Sha1::digest(&torrent[start..end])), "d69f91e6b2ae4c542468d1073a71d4ea13879a7f");
+    }
+}
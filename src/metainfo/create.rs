@@ -0,0 +1,178 @@
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_bytes::ByteBuf;
+
+use crate::metainfo::{File, FileLayout, Info, TorrentMetaInfo};
+use crate::storage::{hash_pieces, FileStorage};
+
+const MIN_PIECE_LENGTH : u64 = 16 * 1024;
+const MAX_PIECE_LENGTH : u64 = 16 * 1024 * 1024;
+// Pieces aimed for when the piece length is picked for us, keeps the .torrent file small
+const TARGET_PIECE_COUNT : u64 = 1500;
+
+// Builds the metainfo of a file or a directory, every file under a directory is part of the torrent.
+// https://wiki.theory.org/BitTorrentSpecification#Metainfo_File_Structure
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path : PathBuf,
+    trackers : Vec<Vec<String>>,
+    piece_length : Option<u64>,
+    comment : Option<String>,
+    created_by : Option<String>,
+    creation_date : Option<i64>,
+    private : bool,
+    web_seeds : Vec<String>
+}
+
+impl TorrentBuilder {
+    pub fn new(path : &Path) -> Self {
+        TorrentBuilder {
+            path: path.to_path_buf(),
+            trackers: vec![],
+            piece_length: None,
+            comment: None,
+            created_by: Some(format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+            creation_date: None,
+            private: false,
+            web_seeds: vec![]
+        }
+    }
+
+    // Each tracker gets its own tier, in the order they are added
+    pub fn with_tracker(mut self, tracker : &str) -> Self {
+        self.trackers.push(vec![tracker.to_string()]);
+        self
+    }
+
+    // Trackers of a tier are tried in random order, the next tier only when none of them answers
+    // https://www.bittorrent.org/beps/bep_0012.html
+    pub fn with_tracker_tier(mut self, tier : Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    // A power of two of at least 16 KiB, picked from the total size when not given
+    pub fn with_piece_length(mut self, piece_length : u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn with_comment(mut self, comment : &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by : &str) -> Self {
+        self.created_by = Some(created_by.to_string());
+        self
+    }
+
+    // Seconds since the epoch, now when not given
+    pub fn with_creation_date(mut self, creation_date : i64) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    // Peers only come from the trackers, no DHT nor peer exchange
+    // https://www.bittorrent.org/beps/bep_0027.html
+    pub fn with_private(mut self, private : bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    // https://www.bittorrent.org/beps/bep_0019.html
+    pub fn with_web_seed(mut self, url : &str) -> Self {
+        self.web_seeds.push(url.to_string());
+        self
+    }
+
+    // Reads every file to hash its pieces, on as many cores as there are
+    pub fn build(&self) -> Result<TorrentMetaInfo, Box<dyn std::error::Error>> {
+        let announce = self.trackers.first().and_then(|tier| tier.first()).cloned()
+            .ok_or("A torrent needs at least one tracker")?;
+        let name = self.path.file_name().and_then(|name| name.to_str())
+            .ok_or_else(|| format!("Invalid torrent name: {}", self.path.display()))?
+            .to_string();
+        let (length, files) = if self.path.is_dir() {
+            let mut files : Vec<File> = vec![];
+            Self::walk(&self.path, &mut vec![], &mut files)?;
+            if files.is_empty() {
+                return Err(format!("No files under {}", self.path.display()).into());
+            }
+            (None, Some(files))
+        } else {
+            (Some(fs::metadata(&self.path)?.len()), None)
+        };
+
+        let mut info = Info {
+            piece_length: 0,
+            pieces: ByteBuf::new(),
+            private: self.private.then_some(1),
+            name,
+            length,
+            md5sum: None,
+            files,
+            meta_version: None,
+            file_tree: None,
+            raw: None
+        };
+        info.piece_length = match self.piece_length {
+            Some(piece_length) if piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two() =>
+                return Err(format!("Piece length {piece_length} is not a power of two of at least {MIN_PIECE_LENGTH}").into()),
+            Some(piece_length) => piece_length,
+            None => Self::pick_piece_length(info.total_length())
+        };
+        // The layout starts with the torrent's name, the files are read from where they are
+        let parent = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let storage = FileStorage::open(FileLayout::new(&info)?, parent);
+        info.pieces = ByteBuf::from(hash_pieces(&storage)?.concat());
+
+        let tracker_count : usize = self.trackers.iter().map(Vec::len).sum();
+        Ok(TorrentMetaInfo {
+            info,
+            announce,
+            announce_list: (tracker_count > 1).then(|| self.trackers.clone()),
+            creation_date: Some(self.creation_date.unwrap_or_else(|| {
+                SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64)
+            })),
+            comment: self.comment.clone(),
+            created_by: self.created_by.clone(),
+            encoding: None,
+            url_list: (!self.web_seeds.is_empty()).then(|| self.web_seeds.clone()),
+            piece_layers: None
+        })
+    }
+
+    // Files in path order so the same directory always gives the same torrent
+    fn walk(directory : &Path, prefix : &mut Vec<String>, files : &mut Vec<File>) -> Result<(), Error> {
+        let mut entries : Vec<fs::DirEntry> = fs::read_dir(directory)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().into_string().map_err(|name| Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("File name is not valid UTF-8: {}", name.to_string_lossy()),
+            ))?;
+            let metadata = fs::metadata(entry.path())?;
+            // A link to a directory can lead back to one of its parents, linked files are fine
+            if metadata.is_dir() && entry.file_type()?.is_symlink() {
+                continue;
+            }
+            prefix.push(name);
+            if metadata.is_dir() {
+                Self::walk(&entry.path(), prefix, files)?;
+            } else {
+                files.push(File { length: metadata.len() as i64, md5sum: None, path: prefix.clone(), attr: None });
+            }
+            prefix.pop();
+        }
+        Ok(())
+    }
+
+    fn pick_piece_length(total_length : u64) -> u64 {
+        total_length.div_ceil(TARGET_PIECE_COUNT).next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+    }
+}
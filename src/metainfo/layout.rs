@@ -0,0 +1,170 @@
+use std::io::Error;
+use std::path::{Component, Path, PathBuf};
+use sha1::{Digest, Sha1};
+
+use crate::metainfo::{piece_root, Info, MERKLE_BLOCK_SIZE};
+
+// A file of the torrent and where it sits in the concatenated torrent data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path : PathBuf,
+    pub length : u64,
+    pub offset : u64,
+    // Zeros aligning the next file on a piece boundary, never read from nor written to disk
+    pub padding : bool
+}
+
+// The part of a file covered by a range of torrent data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file_index : usize,
+    pub file_offset : u64,
+    pub data_offset : u64,
+    pub length : u64
+}
+
+// Maps pieces and blocks to the files they belong to. Torrent data is the concatenation of
+// every file in order, so a single block can start in one file and end in the next one.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub files : Vec<FileEntry>,
+    pub piece_length : u64,
+    pub total_length : u64,
+    // Pieces are checked against v2 merkle trees instead of SHA-1, hybrid torrents keep SHA-1
+    pub merkle_hashes : bool
+}
+
+impl FileLayout {
+    // Paths are relative to the download directory: a single file torrent is stored as `name`,
+    // a multi file torrent as a directory tree rooted at `name`. v2 only torrents start every file on
+    // a piece boundary, the gaps are padding.
+    pub fn new(info : &Info) -> Result<Self, Error> {
+        if info.piece_length == 0 {
+            return Err(Error::new(std::io::ErrorKind::InvalidData, "Piece length is 0"));
+        }
+        let root = Self::sanitize_component(&info.name)?;
+        let mut files : Vec<FileEntry> = vec![];
+        let mut offset = 0u64;
+        match &info.files {
+            None if !info.is_v1() && info.is_v2() => {
+                let v2_files = info.v2_files();
+                let single_file = v2_files.len() == 1 && v2_files[0].path.len() == 1;
+                for (file_index, file) in v2_files.iter().enumerate() {
+                    let mut path = PathBuf::from(&root);
+                    if !single_file {
+                        for component in &file.path {
+                            path.push(Self::sanitize_component(component)?);
+                        }
+                    }
+                    files.push(FileEntry { path, length: file.length, offset, padding: false });
+                    offset += file.length;
+                    let padding = offset.next_multiple_of(info.piece_length) - offset;
+                    if padding > 0 && file_index + 1 < v2_files.len() {
+                        let path = PathBuf::from(&root).join(".pad").join(padding.to_string());
+                        files.push(FileEntry { path, length: padding, offset, padding: true });
+                        offset += padding;
+                    }
+                }
+            },
+            None => {
+                let length = info.length.unwrap_or(0);
+                files.push(FileEntry { path: PathBuf::from(root), length, offset, padding: false });
+                offset += length;
+            },
+            Some(torrent_files) => {
+                for file in torrent_files {
+                    let mut path = PathBuf::from(&root);
+                    for component in &file.path {
+                        path.push(Self::sanitize_component(component)?);
+                    }
+                    let length = file.length as u64;
+                    let padding = file.attr.as_ref().is_some_and(|attr| attr.contains('p'));
+                    files.push(FileEntry { path, length, offset, padding });
+                    offset += length;
+                }
+            }
+        }
+
+        Ok(FileLayout {
+            files,
+            piece_length: info.piece_length,
+            total_length: offset,
+            merkle_hashes: !info.is_v1() && info.is_v2()
+        })
+    }
+
+    // Indexes of the files actually stored, padding aside
+    pub fn data_files(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.files.len()).filter(|&file_index| !self.files[file_index].padding)
+    }
+
+    // Hash of a piece to compare with the one in the metainfo: SHA-1, or the root of the piece's merkle subtree.
+    // A v2 piece covers a single file, the padding after the end of the file isn't hashed.
+    pub fn piece_hash(&self, piece_index : usize, piece_data : &[u8]) -> Vec<u8> {
+        if !self.merkle_hashes {
+            return Sha1::digest(piece_data).to_vec();
+        }
+        let spans = self.spans(piece_index, 0, piece_data.len() as u64);
+        let Some(span) = spans.iter().find(|span| !self.files[span.file_index].padding) else {
+            return piece_root(&[], 1).to_vec();
+        };
+        let file_length = self.files[span.file_index].length;
+        let leaf_count = if file_length <= self.piece_length {
+            file_length.div_ceil(MERKLE_BLOCK_SIZE).next_power_of_two()
+        } else {
+            self.piece_length / MERKLE_BLOCK_SIZE
+        };
+        let file_data = &piece_data[span.data_offset as usize..(span.data_offset + span.length) as usize];
+        piece_root(file_data, leaf_count as usize).to_vec()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    pub fn piece_size(&self, piece_index : usize) -> u64 {
+        let piece_start = piece_index as u64 * self.piece_length;
+        self.piece_length.min(self.total_length.saturating_sub(piece_start))
+    }
+
+    // Split `length` bytes starting at `begin` inside piece `piece_index` into per file spans
+    pub fn spans(&self, piece_index : usize, begin : u64, length : u64) -> Vec<FileSpan> {
+        let start = piece_index as u64 * self.piece_length + begin;
+        let end = (start + length).min(self.total_length);
+        let mut spans : Vec<FileSpan> = vec![];
+        for (file_index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.length == 0 {
+                continue;
+            }
+            if file.offset >= end {
+                break;
+            }
+            let span_start = start.max(file.offset);
+            let span_end = end.min(file_end);
+            spans.push(FileSpan {
+                file_index,
+                file_offset: span_start - file.offset,
+                data_offset: span_start - start,
+                length: span_end - span_start
+            });
+        }
+        spans
+    }
+
+    pub fn full_path(&self, download_directory : &Path, file_index : usize) -> PathBuf {
+        download_directory.join(&self.files[file_index].path)
+    }
+
+    // Refuse anything that could escape the download directory
+    fn sanitize_component(component : &str) -> Result<String, Error> {
+        let mut components = Path::new(component).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(component.to_string()),
+            _ => Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid path component in torrent: {component:?}"),
+            ))
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+use crate::metainfo::{Info, ParserError, Sha256Hash, TorrentMetaInfo};
+
+// https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    // The v1 info hash, or the v2 one truncated to 20 bytes when the link only has that one
+    pub info_hash : [u8; 20],
+    // https://www.bittorrent.org/beps/bep_0052.html#magnet-links
+    pub info_hash_v2 : Option<Sha256Hash>,
+    pub display_name : Option<String>,
+    pub trackers : Vec<String>,
+    pub peers : Vec<SocketAddr>
+}
+
+impl Magnet {
+    pub fn parse(uri : &str) -> Result<Self, ParserError> {
+        let invalid = |reason : &str| ParserError::InvalidMagnetLink(format!("{reason}: {uri}"));
+        let query = uri.strip_prefix("magnet:?").ok_or_else(|| invalid("not a magnet link"))?;
+
+        let mut info_hash : Option<[u8; 20]> = None;
+        let mut info_hash_v2 : Option<Sha256Hash> = None;
+        let mut display_name : Option<String> = None;
+        let mut trackers : Vec<String> = vec![];
+        let mut peers : Vec<SocketAddr> = vec![];
+        for parameter in query.split('&') {
+            let Some((key, value)) = parameter.split_once('=') else { continue };
+            let value = urlencoding::decode(value).map_err(|_| invalid("invalid percent encoding"))?.into_owned();
+            match key {
+                "xt" => {
+                    // Other kinds of exact topic are left alone
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(Self::decode_info_hash(hash).ok_or_else(|| invalid("invalid info hash"))?);
+                    } else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+                        info_hash_v2 = Some(Self::decode_multihash(hash).ok_or_else(|| invalid("invalid v2 info hash"))?);
+                    }
+                },
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                // Peers given by host name are left alone, we only dial addresses
+                "x.pe" => peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash.or_else(|| Some(info_hash_v2?[..20].try_into().unwrap()));
+        Ok(Magnet {
+            info_hash: info_hash.ok_or_else(|| invalid("missing urn:btih or urn:btmh exact topic"))?,
+            info_hash_v2,
+            display_name,
+            trackers,
+            peers
+        })
+    }
+
+    // Build a metainfo around an info dictionary fetched from the swarm, every tracker gets its own tier
+    pub fn to_metainfo(&self, info : Info) -> TorrentMetaInfo {
+        TorrentMetaInfo {
+            info,
+            announce: self.trackers.first().cloned().unwrap_or_default(),
+            announce_list: match self.trackers.len() {
+                0 | 1 => None,
+                _ => Some(self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect())
+            },
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            url_list: None,
+            piece_layers: None
+        }
+    }
+
+    // The info hash is either 40 hex characters or 32 base32 characters
+    fn decode_info_hash(hash : &str) -> Option<[u8; 20]> {
+        let bytes = match hash.len() {
+            40 => base16ct::mixed::decode_vec(hash).ok()?,
+            32 => data_encoding::BASE32.decode(hash.to_uppercase().as_bytes()).ok()?,
+            _ => return None
+        };
+        <[u8; 20]>::try_from(bytes).ok()
+    }
+
+    // A hex encoded multihash, only SHA-256 (code 0x12, 32 bytes long) is used for torrents
+    fn decode_multihash(hash : &str) -> Option<Sha256Hash> {
+        let bytes = base16ct::mixed::decode_vec(hash.strip_prefix("1220")?).ok()?;
+        <Sha256Hash>::try_from(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH : [u8; 20] = [
+        0xd6, 0x9f, 0x91, 0xe6, 0xb2, 0xae, 0x4c, 0x54, 0x24, 0x68,
+        0xd1, 0x07, 0x3a, 0x71, 0xd4, 0xea, 0x13, 0x87, 0x9a, 0x7f
+    ];
+    const INFO_HASH_V2 : &str = "89de0af3a50fffe6ac361ef0ec04e400a059494ef7b4fb35e0c44b0dd2579b3c";
+
+    fn info_hash_v2() -> Sha256Hash {
+        base16ct::lower::decode_vec(INFO_HASH_V2).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn parses_info_hashes() {
+        let info_hash_v2 = info_hash_v2();
+        let truncated_v2 : [u8; 20] = info_hash_v2[..20].try_into().unwrap();
+        for (uri, info_hash, expected_v2) in [
+            ("magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f".to_string(), INFO_HASH, None),
+            ("magnet:?xt=urn:btih:D69F91E6B2AE4C542468D1073A71D4EA13879A7F".to_string(), INFO_HASH, None),
+            ("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7".to_string(), INFO_HASH, None),
+            ("magnet:?xt=urn:btih:22pzdzvsvzgfijdi2edtu4ou5ijypgt7".to_string(), INFO_HASH, None),
+            // A v2 only link is known by its truncated hash, a hybrid one by its v1 hash
+            (format!("magnet:?xt=urn:btmh:1220{INFO_HASH_V2}"), truncated_v2, Some(info_hash_v2)),
+            (format!("magnet:?xt=urn:btmh:1220{INFO_HASH_V2}&xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f"), INFO_HASH, Some(info_hash_v2))
+        ] {
+            let magnet = Magnet::parse(&uri).unwrap();
+            assert_eq!(magnet.info_hash, info_hash, "{uri}");
+            assert_eq!(magnet.info_hash_v2, expected_v2, "{uri}");
+        }
+    }
+
+    #[test]
+    fn parses_trackers_name_and_peers() {
+        let magnet = Magnet::parse(concat!(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample%20file.txt",
+            "&tr=http%3A%2F%2Ftracker.example%2Fannounce%3Fkey%3D1&tr=udp://tracker.example:6969",
+            "&x.pe=127.0.0.1:6881&x.pe=[::1]:6882&x.pe=peer.example:6883&xt=urn:sha1:ignored&unknown"
+        )).unwrap();
+        assert_eq!(magnet.display_name.as_deref(), Some("sample file.txt"));
+        assert_eq!(magnet.trackers, ["http://tracker.example/announce?key=1", "udp://tracker.example:6969"]);
+        assert_eq!(magnet.peers, ["127.0.0.1:6881".parse::<SocketAddr>().unwrap(), "[::1]:6882".parse().unwrap()]);
+    }
+
+    #[test]
+    fn rejects_invalid_links() {
+        for uri in [
+            "http://example.com/?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?dn=no+topic&tr=http://tracker.example/announce",
+            "magnet:?xt=urn:sha1:d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7",
+            "magnet:?xt=urn:btih:z69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT1",
+            "magnet:?xt=urn:btmh:1114d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "magnet:?xt=urn:btmh:122089de0af3a50fffe6ac361ef0ec04e400a059494ef7b4fb35e0c44b0dd2579b",
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=%FF"
+        ] {
+            assert!(matches!(Magnet::parse(uri), Err(ParserError::InvalidMagnetLink(_))), "{uri}");
+        }
+    }
+}
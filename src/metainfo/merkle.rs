@@ -0,0 +1,203 @@
+use sha2::{Digest, Sha256};
+
+// v2 torrents hash every file as a merkle tree of SHA-256 hashes, the leaves being the hashes of its
+// 16 KiB blocks. Leaves past the end of the file are zeros, up to the next power of two.
+// https://www.bittorrent.org/beps/bep_0052.html
+pub const MERKLE_BLOCK_SIZE : u64 = 16 * 1024;
+
+pub type Sha256Hash = [u8; 32];
+
+// Hashes of the 16 KiB blocks of `data`, the last one may be shorter
+pub fn block_hashes(data : &[u8]) -> Vec<Sha256Hash> {
+    data.chunks(MERKLE_BLOCK_SIZE as usize).map(|block| Sha256::digest(block).into()).collect()
+}
+
+pub fn hash_pair(left : &Sha256Hash, right : &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Root of a subtree of `leaf_count` zero leaves, what stands for the part of a tree past the end of a file
+pub fn padding_hash(leaf_count : usize) -> Sha256Hash {
+    let mut hash = [0; 32];
+    let mut width = 1;
+    while width < leaf_count {
+        hash = hash_pair(&hash, &hash);
+        width *= 2;
+    }
+    hash
+}
+
+// Root of a tree of `leaf_count` leaves, a power of two, `leaves` being the first ones and `padding` the others
+pub fn merkle_root(leaves : &[Sha256Hash], leaf_count : usize, padding : Sha256Hash) -> Sha256Hash {
+    let mut layer : Vec<Sha256Hash> = leaves.to_vec();
+    let mut padding = padding;
+    let mut width = leaf_count.max(1);
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(padding);
+        }
+        layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+        padding = hash_pair(&padding, &padding);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(padding)
+}
+
+// Hash of a piece of a file spread over `leaf_count` blocks. The piece of a file smaller than a piece is the
+// whole tree and only takes as many leaves as it needs, the last piece of a larger file takes as many as the others.
+pub fn piece_root(data : &[u8], leaf_count : usize) -> Sha256Hash {
+    merkle_root(&block_hashes(data), leaf_count, [0; 32])
+}
+
+// Root of the tree of a file from its piece layer, the hashes of its pieces of `piece_length` bytes
+pub fn piece_layer_root(piece_layer : &[Sha256Hash], piece_length : u64) -> Sha256Hash {
+    let padding = padding_hash((piece_length / MERKLE_BLOCK_SIZE) as usize);
+    merkle_root(piece_layer, piece_layer.len().next_power_of_two(), padding)
+}
+
+// Whether hashes received for a hash request lead to `root`: `length` hashes of a layer from `index`,
+// then the uncle hashes from the subtree they form up to the root
+pub fn proof_leads_to(root : &Sha256Hash, index : usize, length : usize, hashes : &[Sha256Hash]) -> bool {
+    if length == 0 || !length.is_power_of_two() || !index.is_multiple_of(length) || hashes.len() < length {
+        return false;
+    }
+    let (layer, uncles) = hashes.split_at(length);
+    let mut node = merkle_root(layer, length, [0; 32]);
+    let mut position = index / length;
+    for uncle in uncles {
+        node = match position % 2 {
+            0 => hash_pair(&node, uncle),
+            _ => hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    position == 0 && node == *root
+}
+
+// The tree of a file from its piece layer up, enough to give other peers the hashes they ask for
+// to check the pieces they download without the whole piece layer
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    // Layer of the piece hashes, counted from the blocks
+    base_layer : u32,
+    // From the piece layer to the root, each one padded to a power of two
+    layers : Vec<Vec<Sha256Hash>>
+}
+
+impl MerkleTree {
+    pub fn from_piece_layer(piece_layer : &[Sha256Hash], piece_length : u64) -> Self {
+        let blocks_per_piece = (piece_length / MERKLE_BLOCK_SIZE).max(1);
+        let mut layer = piece_layer.to_vec();
+        layer.resize(piece_layer.len().next_power_of_two(), padding_hash(blocks_per_piece as usize));
+        let mut layers = vec![layer];
+        while layers.last().unwrap().len() > 1 {
+            let layer = layers.last().unwrap().chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+            layers.push(layer);
+        }
+        MerkleTree {
+            base_layer: blocks_per_piece.trailing_zeros(),
+            layers
+        }
+    }
+
+    pub fn root(&self) -> Sha256Hash {
+        self.layers.last().unwrap()[0]
+    }
+
+    // `length` hashes of layer `base_layer` from `index`, followed by the uncle hashes proving them up to
+    // `proof_layers` layers above. None when the tree doesn't go that deep or the range is not aligned.
+    pub fn hashes(&self, base_layer : u32, index : usize, length : usize, proof_layers : u32) -> Option<Vec<Sha256Hash>> {
+        let layer = self.layers.get(base_layer.checked_sub(self.base_layer)? as usize)?;
+        if length == 0 || !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > layer.len() {
+            return None;
+        }
+        let mut hashes = layer[index..index + length].to_vec();
+        // The uncles start above the subtree the hashes form, the root is never sent
+        let subtree_layer = (base_layer - self.base_layer) as usize + length.trailing_zeros() as usize;
+        let mut position = index / length;
+        for layer in self.layers.iter().skip(subtree_layer).take(proof_layers as usize) {
+            if layer.len() == 1 {
+                break;
+            }
+            hashes.push(layer[position ^ 1]);
+            position /= 2;
+        }
+        Some(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_LENGTH : u64 = 2 * MERKLE_BLOCK_SIZE;
+
+    fn piece_layer(piece_count : u8) -> Vec<Sha256Hash> {
+        (0..piece_count).map(|piece| Sha256::digest([piece]).into()).collect()
+    }
+
+    #[test]
+    fn tree_root_matches_piece_layer_root() {
+        for piece_count in [1, 2, 3, 5, 8] {
+            let piece_layer = piece_layer(piece_count);
+            let tree = MerkleTree::from_piece_layer(&piece_layer, PIECE_LENGTH);
+            assert_eq!(tree.root(), piece_layer_root(&piece_layer, PIECE_LENGTH));
+        }
+    }
+
+    #[test]
+    fn piece_layer_root_pads_with_empty_pieces() {
+        let piece_layer = piece_layer(3);
+        let empty_piece = merkle_root(&[], 2, [0; 32]);
+        let root = hash_pair(&hash_pair(&piece_layer[0], &piece_layer[1]), &hash_pair(&piece_layer[2], &empty_piece));
+        assert_eq!(piece_layer_root(&piece_layer, PIECE_LENGTH), root);
+    }
+
+    #[test]
+    fn hashes_prove_up_to_the_root() {
+        let piece_layer = piece_layer(7);
+        let tree = MerkleTree::from_piece_layer(&piece_layer, PIECE_LENGTH);
+        let base_layer = PIECE_LENGTH.trailing_zeros() - MERKLE_BLOCK_SIZE.trailing_zeros();
+        for (index, length, proof_layers) in [(0, 8, 0), (0, 4, 1), (4, 4, 1), (2, 2, 2), (5, 1, 3)] {
+            let hashes = tree.hashes(base_layer, index, length, proof_layers).unwrap();
+            assert_eq!(hashes.len(), length + proof_layers as usize);
+            assert_eq!(hashes[..length.min(7 - index)], piece_layer[index..(index + length).min(7)]);
+            assert!(proof_leads_to(&tree.root(), index, length, &hashes));
+        }
+    }
+
+    #[test]
+    fn proof_stops_at_the_root() {
+        let tree = MerkleTree::from_piece_layer(&piece_layer(4), PIECE_LENGTH);
+        let hashes = tree.hashes(1, 0, 2, 5).unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert!(proof_leads_to(&tree.root(), 0, 2, &hashes));
+    }
+
+    #[test]
+    fn hashes_rejects_invalid_requests() {
+        let tree = MerkleTree::from_piece_layer(&piece_layer(4), PIECE_LENGTH);
+        // Misaligned, not a power of two, out of range, below the piece layer, above the root
+        assert!(tree.hashes(1, 1, 2, 0).is_none());
+        assert!(tree.hashes(1, 0, 3, 0).is_none());
+        assert!(tree.hashes(1, 4, 1, 0).is_none());
+        assert!(tree.hashes(1, 0, 0, 0).is_none());
+        assert!(tree.hashes(0, 0, 1, 0).is_none());
+        assert!(tree.hashes(4, 0, 1, 0).is_none());
+    }
+
+    #[test]
+    fn proof_rejects_wrong_hashes() {
+        let tree = MerkleTree::from_piece_layer(&piece_layer(4), PIECE_LENGTH);
+        let mut hashes = tree.hashes(1, 2, 1, 2).unwrap();
+        assert!(!proof_leads_to(&[1; 32], 2, 1, &hashes));
+        // Proving another index, or with a proof that stops short of the root
+        assert!(!proof_leads_to(&tree.root(), 3, 1, &hashes));
+        assert!(!proof_leads_to(&tree.root(), 2, 1, &hashes[..2]));
+        hashes[0][0] ^= 1;
+        assert!(!proof_leads_to(&tree.root(), 2, 1, &hashes));
+    }
+}
@@ -1,25 +1,44 @@
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
-use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 
+use crate::metainfo::{dictionary_value_span, FileTreeNode};
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct File {
     pub length : i64,
     pub md5sum : Option<String>,
-    pub path : Vec<String>
+    pub path : Vec<String>,
+    // "p" marks a padding file, only there to align the next file on a piece boundary
+    // https://www.bittorrent.org/beps/bep_0047.html
+    pub attr : Option<String>
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct Info {
     #[serde(rename = "piece length")]
     pub piece_length : u64,
+    // Not there in v2 only torrents
+    #[serde(default)]
     pub pieces : ByteBuf,
     pub private : Option<u8>,
     pub name : String,
     pub length : Option<u64>,
     pub md5sum : Option<String>,
-    pub files : Option<Vec<File>>
+    pub files : Option<Vec<File>>,
+    // 2 for v2 and hybrid torrents, along with the file tree
+    #[serde(rename = "meta version")]
+    pub meta_version : Option<u8>,
+    #[serde(rename = "file tree")]
+    pub file_tree : Option<BTreeMap<String, FileTreeNode>>,
+    // The info dictionary exactly as it was encoded in the .torrent file or sent by peers, the info hash
+    // is computed over it. Encoding the fields above again would drop the keys they don't model.
+    #[serde(skip)]
+    pub raw : Option<Vec<u8>>
 }
 
 // https://wiki.theory.org/BitTorrentSpecification#Metainfo_File_Structure
@@ -35,6 +54,27 @@ pub struct TorrentMetaInfo {
     #[serde(rename = "created by")]
     pub created_by : Option<String>,
     pub encoding : Option<String>,
+    // Web seeds, either a single url or a list of them
+    // https://www.bittorrent.org/beps/bep_0019.html
+    #[serde(rename = "url-list", default, deserialize_with = "one_or_many")]
+    pub url_list : Option<Vec<String>>,
+    // Hashes of the pieces of each file of a v2 torrent bigger than a piece, by pieces root
+    #[serde(rename = "piece layers")]
+    pub piece_layers : Option<BTreeMap<ByteBuf, ByteBuf>>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>)
+}
+
+fn one_or_many<'de, D : Deserializer<'de>>(deserializer : D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<OneOrMany>::deserialize(deserializer)?.map(|urls| match urls {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls
+    }))
 }
 
 fn bytes_to_hex_string(bytes: &[u8]) -> String {
@@ -42,9 +82,19 @@ fn bytes_to_hex_string(bytes: &[u8]) -> String {
 }
 
 impl TorrentMetaInfo {
+    // Write it as a .torrent file
+    pub fn save(&self, path : &Path) -> Result<(), std::io::Error> {
+        let mut bytes = serde_bencode::to_bytes(self).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        // The info dictionary is written back as it came, not as encoded again
+        if let (Some(raw), Some((start, end))) = (&self.info.raw, dictionary_value_span(&bytes, b"info")) {
+            bytes.splice(start..end, raw.iter().copied());
+        }
+        fs::write(path, bytes)
+    }
+
     pub fn urlencode_info_hash(&self) -> String {
         let info_hash_raw = self.info.hash_raw();
-        urlencoding::encode_binary(&*info_hash_raw).to_string()
+        urlencoding::encode_binary(&info_hash_raw).to_string()
     }
 }
 
@@ -54,12 +104,24 @@ impl Display for TorrentMetaInfo {
         writeln!(f, "Tracker url: {}", self.announce)?;
         writeln!(f, "name: {}", self.info.name)?;
         writeln!(f, "piece length: {}", self.info.piece_length)?;
-        if let Some(length) = self.info.length {
-            writeln!(f, "length: {}", length)?;
-        } else {
-            writeln!(f, "length: None")?;
+        writeln!(f, "length: {}", self.info.total_length())?;
+        if let Some(files) = &self.info.files {
+            writeln!(f, "files: ")?;
+            for file in files {
+                writeln!(f, "{} ({} bytes)", file.path.join("/"), file.length)?;
+            }
+        } else if !self.info.is_v1() && self.info.v2_files().len() > 1 {
+            writeln!(f, "files: ")?;
+            for file in self.info.v2_files() {
+                writeln!(f, "{} ({} bytes)", file.path.join("/"), file.length)?;
+            }
+        }
+        if self.info.is_v1() || !self.info.is_v2() {
+            writeln!(f, "info hash: {}", info_hash)?;
+        }
+        if let Some(hash_v2) = self.info.hash_v2() {
+            writeln!(f, "info hash v2: {}", base16ct::lower::encode_string(&hash_v2))?;
         }
-        writeln!(f, "info hash: {}", info_hash)?;
         if let Some(md5sum) = self.info.md5sum.clone() {
             write!(f, "md5sum: {}", md5sum)?;
         } else {
@@ -75,12 +137,40 @@ impl Display for TorrentMetaInfo {
 }
 
 impl Info {
+    // Single file torrents give the length directly, multi file torrents give one per file
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length as u64).sum(),
+            // Files of v2 torrents start on a piece boundary, counted with their padding like in hybrid torrents
+            None if !self.is_v1() => {
+                let files = self.v2_files();
+                let padded : u64 = files.iter().rev().skip(1).map(|file| file.length.next_multiple_of(self.piece_length)).sum();
+                padded + files.last().map_or(0, |file| file.length)
+            },
+            None => self.length.unwrap_or(0)
+        }
+    }
+
+    // Decode an info dictionary and keep its encoding for the info hash
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self, serde_bencode::Error> {
+        let mut info : Info = serde_bencode::from_bytes(bytes)?;
+        info.raw = Some(bytes.to_vec());
+        Ok(info)
+    }
+
+    // The info dictionary as it was encoded, encoded from the fields when built by us
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.raw {
+            Some(raw) => raw.clone(),
+            None => serde_bencode::to_bytes(self).unwrap()
+        }
+    }
+
     pub fn hash_base16(&self) -> String {
-        let hash = Sha1::digest(serde_bencode::to_bytes(self).unwrap());
-        base16ct::lower::encode_string(&hash)
+        base16ct::lower::encode_string(&self.hash_raw())
     }
 
     pub fn hash_raw(&self) -> Vec<u8> {
-        Sha1::digest(serde_bencode::to_bytes(self).unwrap()).to_vec()
+        Sha1::digest(self.to_bytes()).to_vec()
     }
 }
@@ -1,4 +1,16 @@
+mod bencode;
+pub use bencode::*;
 mod parser;
 pub use parser::*;
 mod metainfo;
 pub use metainfo::*;
+mod layout;
+pub use layout::*;
+mod magnet;
+pub use magnet::*;
+mod create;
+pub use create::*;
+mod merkle;
+pub use merkle::*;
+mod v2;
+pub use v2::*;
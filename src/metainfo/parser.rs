@@ -1,12 +1,20 @@
 use std::fs;
 use std::path::PathBuf;
-use crate::metainfo::TorrentMetaInfo;
+use crate::metainfo::{dictionary_value, TorrentMetaInfo};
 
 #[derive(Debug)]
 pub enum ParserError {
     InvalidBencodedData,
+    // Pieces of 0 bytes can't hold any data
+    InvalidPieceLength,
+    // A v2 file with data has no pieces root
+    MissingPiecesRoot,
+    // A v2 file's piece layer is missing or doesn't lead to its pieces root
+    InvalidPieceLayers,
     #[allow(dead_code)]
-    CannotReadFile(String)
+    CannotReadFile(String),
+    #[allow(dead_code)]
+    InvalidMagnetLink(String)
 }
 
 pub struct Parser {
@@ -28,13 +36,24 @@ impl Parser {
             Ok(value) => value,
             Err(_) => return Err(ParserError::CannotReadFile(self.file_name.clone()))
         };
-        let deserialized : TorrentMetaInfo = match serde_bencode::from_bytes(&file_content) {
+        let mut deserialized : TorrentMetaInfo = match serde_bencode::from_bytes(&file_content) {
             Ok(value) => value,
             Err(err) => {
                 println!("{}", err);
                 return Err(ParserError::InvalidBencodedData)
             }
         };
+        // Kept as is for the info hash
+        deserialized.info.raw = dictionary_value(&file_content, b"info").map(<[u8]>::to_vec);
+        if deserialized.info.piece_length == 0 {
+            return Err(ParserError::InvalidPieceLength);
+        }
+        if deserialized.info.is_v2() && !deserialized.info.pieces_roots_valid() {
+            return Err(ParserError::MissingPiecesRoot);
+        }
+        if deserialized.info.is_v2() && !deserialized.piece_layers_valid() {
+            return Err(ParserError::InvalidPieceLayers);
+        }
 
         Ok(deserialized)
     }
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_bytes::{ByteBuf, Bytes};
+use sha2::{Digest, Sha256};
+
+use crate::metainfo::{piece_layer_root, Info, Sha256Hash, TorrentMetaInfo};
+
+// A node of the file tree of a v2 torrent: a directory or, under the empty key, a file
+// https://www.bittorrent.org/beps/bep_0052.html#info-dictionary
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        file : FileTreeEntry
+    },
+    Directory(BTreeMap<String, FileTreeNode>)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FileTreeEntry {
+    pub length : u64,
+    // Root of the merkle tree of the file, empty files have none
+    #[serde(rename = "pieces root")]
+    pub pieces_root : Option<ByteBuf>
+}
+
+// A file of a v2 torrent, in file tree order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path : Vec<String>,
+    pub length : u64,
+    pub pieces_root : Option<Sha256Hash>
+}
+
+impl Info {
+    // Has the v1 fields, a hybrid torrent has both
+    pub fn is_v1(&self) -> bool {
+        self.length.is_some() || self.files.is_some()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    // Joins both the v1 and the v2 swarm, the v1 files are padded so pieces line up with the v2 files
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    // SHA-256 of the info dictionary, only for v2 torrents
+    pub fn hash_v2(&self) -> Option<Sha256Hash> {
+        self.is_v2().then(|| Sha256::digest(self.to_bytes()).into())
+    }
+
+    // The hashes identifying the torrent's swarms, the v1 one first: the SHA-1 info hash for v1 and
+    // the SHA-256 one truncated to 20 bytes for v2, where handshakes and trackers only have room for 20
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut info_hashes : Vec<[u8; 20]> = vec![];
+        if self.is_v1() || !self.is_v2() {
+            info_hashes.push(self.hash_raw().try_into().unwrap());
+        }
+        if let Some(hash_v2) = self.hash_v2() {
+            info_hashes.push(hash_v2[..20].try_into().unwrap());
+        }
+        info_hashes
+    }
+
+    // Every file with data needs a pieces root to check its pieces against
+    pub fn pieces_roots_valid(&self) -> bool {
+        self.v2_files().iter().all(|file| file.length == 0 || file.pieces_root.is_some())
+    }
+
+    // The files of the file tree, depth first in key order. A single file torrent is a file named after the torrent.
+    pub fn v2_files(&self) -> Vec<V2File> {
+        let mut files : Vec<V2File> = vec![];
+        if let Some(file_tree) = &self.file_tree {
+            Self::walk_file_tree(file_tree, &mut vec![], &mut files);
+        }
+        files
+    }
+
+    fn walk_file_tree(directory : &BTreeMap<String, FileTreeNode>, prefix : &mut Vec<String>, files : &mut Vec<V2File>) {
+        for (name, node) in directory {
+            prefix.push(name.clone());
+            match node {
+                FileTreeNode::File { file } => files.push(V2File {
+                    path: prefix.clone(),
+                    length: file.length,
+                    pieces_root: file.pieces_root.as_ref().and_then(|root| root.as_slice().try_into().ok())
+                }),
+                FileTreeNode::Directory(directory) => Self::walk_file_tree(directory, prefix, files)
+            }
+            prefix.pop();
+        }
+    }
+}
+
+impl TorrentMetaInfo {
+    // Expected hash of every piece, v2 torrents hash pieces as merkle trees and their pieces never span files.
+    // A file of a single piece is hashed by its root, the others by their piece layer.
+    pub fn v2_piece_hashes(&self) -> Vec<Sha256Hash> {
+        let piece_length = self.info.piece_length;
+        let mut hashes : Vec<Sha256Hash> = vec![];
+        for file in self.info.v2_files() {
+            let piece_count = file.length.div_ceil(piece_length) as usize;
+            let (Some(pieces_root), true) = (file.pieces_root, file.length > piece_length) else {
+                // A file without pieces root keeps its piece, never passing the hash check, so the others stay in place
+                hashes.extend(vec![file.pieces_root.unwrap_or([0; 32]); piece_count]);
+                continue;
+            };
+            match self.piece_layer(&pieces_root) {
+                Some(piece_layer) if piece_layer.len() == piece_count => hashes.extend(piece_layer),
+                // Unknown pieces never pass the hash check
+                _ => hashes.extend(vec![[0; 32]; piece_count])
+            }
+        }
+        hashes
+    }
+
+    // True when every file of more than one piece has a pieces root, and its piece layer, if there, leads to it.
+    // Missing piece layers can be asked from peers.
+    pub fn piece_layers_valid(&self) -> bool {
+        let piece_length = self.info.piece_length;
+        self.info.v2_files().iter().filter(|file| file.length > piece_length).all(|file| {
+            let Some(pieces_root) = file.pieces_root else { return false };
+            if !self.piece_layers.as_ref().is_some_and(|piece_layers| piece_layers.contains_key(Bytes::new(&pieces_root))) {
+                return true;
+            }
+            self.piece_layer(&pieces_root).is_some_and(|piece_layer| {
+                piece_layer.len() as u64 == file.length.div_ceil(piece_length)
+                    && piece_layer_root(&piece_layer, piece_length) == pieces_root
+            })
+        })
+    }
+
+    // Files of more than one piece whose piece layer we don't have, their pieces can't be checked without it
+    pub fn missing_piece_layers(&self) -> Vec<V2File> {
+        self.info.v2_files().into_iter()
+            .filter(|file| file.length > self.info.piece_length)
+            .filter(|file| file.pieces_root.is_some_and(|pieces_root| self.piece_layer(&pieces_root).is_none()))
+            .collect()
+    }
+
+    // A piece layer received from a peer, already checked against the pieces root
+    pub fn add_piece_layer(&mut self, pieces_root : &Sha256Hash, piece_layer : &[Sha256Hash]) {
+        self.piece_layers.get_or_insert_with(BTreeMap::new)
+            .insert(ByteBuf::from(pieces_root.to_vec()), ByteBuf::from(piece_layer.concat()));
+    }
+
+    // Hashes of the pieces of the file with this root, from the piece layers
+    pub fn piece_layer(&self, pieces_root : &Sha256Hash) -> Option<Vec<Sha256Hash>> {
+        let piece_layer = self.piece_layers.as_ref()?.get(Bytes::new(pieces_root))?;
+        if !piece_layer.len().is_multiple_of(32) {
+            return None;
+        }
+        Some(piece_layer.chunks_exact(32).map(|hash| hash.try_into().unwrap()).collect())
+    }
+}
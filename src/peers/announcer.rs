@@ -0,0 +1,208 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Instant};
+
+use crate::peers::{AnnounceEvent, AnnounceRequest, TrackerManager};
+
+// Wait this long before trying again when no tracker answered
+const RETRY_INTERVAL : Duration = Duration::from_secs(60);
+// Never announce more often than this, whatever the tracker says
+const MIN_ANNOUNCE_INTERVAL : Duration = Duration::from_secs(30);
+// Give up on the stopped event after this long, nobody waits on it
+const STOP_TIMEOUT : Duration = Duration::from_secs(10);
+
+// Transfer counters shared between the peer sessions and the announcer
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded : AtomicU64,
+    downloaded : AtomicU64,
+    left : AtomicU64
+}
+
+impl TransferStats {
+    pub fn new(left : u64) -> Self {
+        TransferStats {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, bytes : u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes : u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, bytes : u64) {
+        self.left.store(bytes, Ordering::Relaxed);
+    }
+}
+
+// Peers a tracker gave for one of the torrent's swarms, they expect a handshake with its info hash
+#[derive(Debug, Clone)]
+pub struct SwarmPeers {
+    pub info_hash : [u8; 20],
+    pub peers : Vec<SocketAddr>
+}
+
+enum AnnouncerCommand {
+    Completed,
+    Reannounce,
+    Stop
+}
+
+// Keeps the trackers informed for the lifetime of a download: started first, then regular
+// announces at the interval the tracker asked for, completed once we have everything and stopped on shutdown.
+pub struct Announcer {
+    commands : mpsc::Sender<AnnouncerCommand>,
+    peers : mpsc::Receiver<SwarmPeers>,
+    task : JoinHandle<()>
+}
+
+impl Announcer {
+    // `other_info_hashes` are announced along with the request's, for the v2 swarm of a hybrid torrent
+    pub fn spawn(trackers : TrackerManager, request : AnnounceRequest, other_info_hashes : Vec<[u8; 20]>, stats : Arc<TransferStats>) -> Self {
+        let (commands_sender, commands_receiver) = mpsc::channel(8);
+        let (peers_sender, peers_receiver) = mpsc::channel(8);
+        let task = tokio::spawn(Self::run(trackers, request, other_info_hashes, stats, commands_receiver, peers_sender));
+        Announcer {
+            commands: commands_sender,
+            peers: peers_receiver,
+            task
+        }
+    }
+
+    // Waits for the peers handed out by the next successful announce
+    pub async fn peers(&mut self) -> Option<SwarmPeers> {
+        self.peers.recv().await
+    }
+
+    pub async fn completed(&self) {
+        let _ = self.commands.send(AnnouncerCommand::Completed).await;
+    }
+
+    // Ask for more peers early, this still honors the tracker's min interval
+    pub async fn reannounce(&self) {
+        let _ = self.commands.send(AnnouncerCommand::Reannounce).await;
+    }
+
+    pub async fn stop(self) {
+        let _ = self.commands.send(AnnouncerCommand::Stop).await;
+        let _ = self.task.await;
+    }
+
+    async fn run(mut trackers : TrackerManager, mut request : AnnounceRequest, other_info_hashes : Vec<[u8; 20]>, stats : Arc<TransferStats>,
+                 mut commands : mpsc::Receiver<AnnouncerCommand>, peers_sender : mpsc::Sender<SwarmPeers>) {
+        // Tracker ids and tier order belong to a swarm, the other swarms get their own copy of the trackers
+        let mut other_swarms : Vec<(TrackerManager, [u8; 20])> = other_info_hashes.into_iter()
+            .map(|info_hash| (trackers.clone(), info_hash))
+            .collect();
+        request.event = Some(AnnounceEvent::Started);
+        let mut started = false;
+        let mut completed = false;
+        let mut next_announce = Instant::now();
+        let mut earliest_announce = Instant::now();
+        loop {
+            tokio::select! {
+                _ = sleep_until(next_announce) => {},
+                command = commands.recv() => match command {
+                    Some(AnnouncerCommand::Completed) => completed = true,
+                    Some(AnnouncerCommand::Reannounce) => {
+                        next_announce = next_announce.min(earliest_announce);
+                        continue;
+                    },
+                    Some(AnnouncerCommand::Stop) | None => break
+                }
+            }
+            // A completed event means nothing to a tracker that never saw us start
+            if std::mem::take(&mut completed) && started {
+                request.event = Some(AnnounceEvent::Completed);
+            }
+
+            Self::update_counters(&mut request, &stats);
+            // A tracker can take long to answer, stopping doesn't wait for it
+            let announces = async {
+                // The other swarms follow the same schedule
+                for (other_trackers, info_hash) in other_swarms.iter_mut() {
+                    let request = AnnounceRequest { info_hash: *info_hash, ..request.clone() };
+                    if let Ok((_, tracker_response)) = other_trackers.announce(&request).await {
+                        let _ = peers_sender.try_send(SwarmPeers { info_hash: *info_hash, peers: tracker_response.peers() });
+                    }
+                }
+                trackers.announce(&request).await
+            };
+            let result = tokio::select! {
+                result = announces => result,
+                _ = Self::until_stopped(&mut commands, &mut completed) => break
+            };
+            match result {
+                Ok((tracker, tracker_response)) => {
+                    if let Some(warning) = tracker_response.warning() {
+                        eprintln!("Tracker {tracker} warning: {warning}");
+                    }
+                    started = true;
+                    request.event = None;
+                    let interval = Duration::from_secs(tracker_response.interval()).max(MIN_ANNOUNCE_INTERVAL);
+                    let min_interval = tracker_response.min_interval().map_or(MIN_ANNOUNCE_INTERVAL, Duration::from_secs);
+                    next_announce = Instant::now() + interval;
+                    earliest_announce = Instant::now() + min_interval.clamp(MIN_ANNOUNCE_INTERVAL, interval);
+                    // Peers nobody asked for are dropped rather than holding up the announces
+                    let _ = peers_sender.try_send(SwarmPeers { info_hash: request.info_hash, peers: tracker_response.peers() });
+                },
+                Err(_) => {
+                    next_announce = Instant::now() + RETRY_INTERVAL;
+                }
+            }
+            // Completed during the announce, tell the trackers right away
+            if completed {
+                next_announce = Instant::now();
+            }
+        }
+
+        if started {
+            request.event = Some(AnnounceEvent::Stopped);
+            Self::update_counters(&mut request, &stats);
+            for (other_trackers, info_hash) in other_swarms.iter_mut() {
+                let request = AnnounceRequest { info_hash: *info_hash, ..request.clone() };
+                let _ = timeout(STOP_TIMEOUT, other_trackers.announce(&request)).await;
+            }
+            let _ = timeout(STOP_TIMEOUT, trackers.announce(&request)).await;
+        }
+    }
+
+    // Follows the commands while an announce is running, returns once told to stop
+    async fn until_stopped(commands : &mut mpsc::Receiver<AnnouncerCommand>, completed : &mut bool) {
+        loop {
+            match commands.recv().await {
+                Some(AnnouncerCommand::Completed) => *completed = true,
+                // An announce is already on its way
+                Some(AnnouncerCommand::Reannounce) => {},
+                Some(AnnouncerCommand::Stop) | None => return
+            }
+        }
+    }
+
+    fn update_counters(request : &mut AnnounceRequest, stats : &TransferStats) {
+        request.uploaded = stats.uploaded();
+        request.downloaded = stats.downloaded();
+        request.left = stats.left();
+    }
+}
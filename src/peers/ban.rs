@@ -0,0 +1,73 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use sha1::{Digest, Sha1};
+
+use crate::peers::BLOCK_MAX;
+
+// Pieces failing the hash check a peer contributed to before it is banned, even when
+// the block comparison can't single it out
+const MAX_HASH_FAILURES : u32 = 3;
+
+#[derive(Debug, Default)]
+struct BanState {
+    banned : HashSet<IpAddr>,
+    failures : HashMap<IpAddr, u32>,
+    // Hash of every block of the pieces that failed and the peer that sent it, by piece
+    suspect_blocks : HashMap<usize, Vec<(usize, IpAddr, [u8; 20])>>
+}
+
+// Bans peers sending corrupt data. When a piece fails the hash check the hash of each block is kept
+// along with the peer that sent it; once the piece is downloaded again and passes, the peers whose
+// blocks differ from the good ones are the culprits. Peers are banned by IP, their port may change.
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    state : Mutex<BanState>
+}
+
+impl SmartBan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_banned(&self, peer_ip : IpAddr) -> bool {
+        self.state.lock().unwrap().banned.contains(&peer_ip)
+    }
+
+    // `contributors` holds the peer that sent each block of the piece, when known. Returns the peers banned because of it
+    pub fn hash_failed(&self, piece_index : usize, piece_data : &[u8], contributors : &[Option<IpAddr>]) -> Vec<IpAddr> {
+        let mut state = self.state.lock().unwrap();
+        let suspect_blocks = state.suspect_blocks.entry(piece_index).or_default();
+        for (block, (data, peer_ip)) in piece_data.chunks(BLOCK_MAX as usize).zip(contributors).enumerate() {
+            if let Some(peer_ip) = peer_ip {
+                suspect_blocks.push((block, *peer_ip, Sha1::digest(data).into()));
+            }
+        }
+
+        let mut banned : Vec<IpAddr> = vec![];
+        for peer_ip in contributors.iter().flatten().copied().collect::<HashSet<IpAddr>>() {
+            let failures = state.failures.entry(peer_ip).or_insert(0);
+            *failures += 1;
+            if *failures >= MAX_HASH_FAILURES && state.banned.insert(peer_ip) {
+                banned.push(peer_ip);
+            }
+        }
+        banned
+    }
+
+    // The piece finally passed the hash check, ban whoever sent a different block for it before
+    pub fn piece_passed(&self, piece_index : usize, piece_data : &[u8]) -> Vec<IpAddr> {
+        let mut state = self.state.lock().unwrap();
+        let Some(suspect_blocks) = state.suspect_blocks.remove(&piece_index) else {
+            return vec![];
+        };
+        let good_blocks : Vec<[u8; 20]> = piece_data.chunks(BLOCK_MAX as usize).map(|data| Sha1::digest(data).into()).collect();
+        let mut banned : Vec<IpAddr> = vec![];
+        for (block, peer_ip, hash) in suspect_blocks {
+            if good_blocks.get(block) != Some(&hash) && state.banned.insert(peer_ip) {
+                banned.push(peer_ip);
+            }
+        }
+        banned
+    }
+}
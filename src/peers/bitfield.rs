@@ -0,0 +1,76 @@
+// https://wiki.theory.org/BitTorrentSpecification#bitfield:_.3Clen.3D0001.2BX.3E.3Cid.3D5.3E.3Cbitfield.3E
+// One bit per piece, the high bit of the first byte being piece 0. Spare bits at the end are cleared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits : Vec<u8>,
+    piece_count : usize
+}
+
+impl Bitfield {
+    pub fn new(piece_count : usize) -> Self {
+        Bitfield {
+            bits: vec![0; piece_count.div_ceil(8)],
+            piece_count
+        }
+    }
+
+    // Returns None when the payload does not fit the torrent, peers sending such a bitfield should be dropped
+    pub fn from_bytes(bytes : &[u8], piece_count : usize) -> Option<Self> {
+        if bytes.len() != piece_count.div_ceil(8) {
+            return None;
+        }
+        let bitfield = Bitfield {
+            bits: bytes.to_vec(),
+            piece_count
+        };
+        if (piece_count..bytes.len() * 8).any(|spare_bit| bitfield.bit(spare_bit)) {
+            return None;
+        }
+        Some(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.piece_count
+    }
+
+    pub fn has(&self, piece_index : usize) -> bool {
+        piece_index < self.piece_count && self.bit(piece_index)
+    }
+
+    pub fn set(&mut self, piece_index : usize) {
+        if piece_index < self.piece_count {
+            self.bits[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, piece_index : usize) {
+        if piece_index < self.piece_count {
+            self.bits[piece_index / 8] &= !(0x80 >> (piece_index % 8));
+        }
+    }
+
+    // Indexes of the pieces set, in order
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.piece_count).filter(|&piece_index| self.bit(piece_index))
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&byte| byte == 0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.piece_count
+    }
+
+    fn bit(&self, index : usize) -> bool {
+        self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use rand::seq::SliceRandom;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
+
+use crate::peers::PieceStore;
+
+// https://wiki.theory.org/BitTorrentSpecification#Choking_and_Optimistic_Unchoking
+// Peers unchoked for their rate, the optimistic unchoke comes on top of those
+pub const REGULAR_UNCHOKE_SLOTS : usize = 3;
+const RECHOKE_INTERVAL : Duration = Duration::from_secs(10);
+// The optimistic unchoke moves to another peer every third round
+const OPTIMISTIC_UNCHOKE_ROUNDS : u32 = 3;
+// A peer that sent us nothing for this long while we wanted something is snubbing us
+const SNUB_TIMEOUT : Duration = Duration::from_secs(60);
+// Peers connected for less than this are three times as likely to get the optimistic unchoke
+const NEW_PEER_AGE : Duration = Duration::from_secs(60);
+
+// Bytes going one way, the rate being refreshed by the choker every round
+#[derive(Debug, Default)]
+pub struct RateCounter {
+    total : AtomicU64,
+    window : AtomicU64,
+    rate : AtomicU64
+}
+
+impl RateCounter {
+    pub fn add(&self, bytes : u64) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        self.window.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // Bytes per second, averaged over the last rounds
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    fn sample(&self, elapsed : Duration) {
+        let bytes = self.window.swap(0, Ordering::Relaxed);
+        let current = (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        let previous = self.rate.load(Ordering::Relaxed);
+        self.rate.store((previous + current) / 2, Ordering::Relaxed);
+    }
+}
+
+// What the choker knows about one peer. The session keeps the counters and interest up to date,
+// the choker decides whether the peer is unchoked and the session tells the peer.
+#[derive(Debug)]
+pub struct ChokedPeer {
+    pub downloaded : RateCounter,
+    pub uploaded : RateCounter,
+    peer_interested : AtomicBool,
+    am_interested : AtomicBool,
+    unchoked : AtomicBool,
+    connected_at : Instant,
+    last_block : Mutex<Instant>,
+    rechoke : Arc<Notify>,
+    // Woken when the choker changes its mind about this peer
+    pub choke_changed : Notify
+}
+
+impl ChokedPeer {
+    fn new(rechoke : Arc<Notify>) -> Self {
+        ChokedPeer {
+            downloaded: RateCounter::default(),
+            uploaded: RateCounter::default(),
+            peer_interested: AtomicBool::new(false),
+            am_interested: AtomicBool::new(false),
+            unchoked: AtomicBool::new(false),
+            connected_at: Instant::now(),
+            last_block: Mutex::new(Instant::now()),
+            rechoke,
+            choke_changed: Notify::new()
+        }
+    }
+
+    pub fn is_unchoked(&self) -> bool {
+        self.unchoked.load(Ordering::Relaxed)
+    }
+
+    pub fn is_peer_interested(&self) -> bool {
+        self.peer_interested.load(Ordering::Relaxed)
+    }
+
+    // Interested peers waiting on a free slot get one without waiting for the next round
+    pub fn set_peer_interested(&self, interested : bool) {
+        if self.peer_interested.swap(interested, Ordering::Relaxed) != interested {
+            self.rechoke.notify_one();
+        }
+    }
+
+    pub fn set_am_interested(&self, interested : bool) {
+        if !self.am_interested.swap(interested, Ordering::Relaxed) && interested {
+            // Snubbing is counted from the moment we start asking
+            *self.last_block.lock().unwrap() = Instant::now();
+        }
+    }
+
+    pub fn record_block(&self, bytes : u64) {
+        self.downloaded.add(bytes);
+        *self.last_block.lock().unwrap() = Instant::now();
+    }
+
+    pub fn is_snubbing(&self) -> bool {
+        self.am_interested.load(Ordering::Relaxed) && self.last_block.lock().unwrap().elapsed() > SNUB_TIMEOUT
+    }
+}
+
+// Decides which peers of a torrent we upload to: the best uploaders to us while we download,
+// the fastest downloaders from us once we seed, plus one optimistic unchoke to find better peers.
+#[derive(Debug)]
+pub struct Choker {
+    peers : Mutex<Vec<Weak<ChokedPeer>>>,
+    optimistic : Mutex<Option<Weak<ChokedPeer>>>,
+    rechoke : Arc<Notify>
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Choker {
+    pub fn new() -> Self {
+        Choker {
+            peers: Mutex::new(vec![]),
+            optimistic: Mutex::new(None),
+            rechoke: Arc::new(Notify::new())
+        }
+    }
+
+    // The peer is forgotten once its session drops the returned handle
+    pub fn register(&self) -> Arc<ChokedPeer> {
+        let peer = Arc::new(ChokedPeer::new(self.rechoke.clone()));
+        self.peers.lock().unwrap().push(Arc::downgrade(&peer));
+        peer
+    }
+
+    // Rechoke every round until aborted, seeding once `store` has every piece
+    pub async fn run(self : Arc<Self>, store : Arc<PieceStore>) {
+        let mut round : u32 = 0;
+        let mut last_round = Instant::now();
+        loop {
+            tokio::select! {
+                _ = sleep_until(last_round + RECHOKE_INTERVAL) => {
+                    let elapsed = last_round.elapsed();
+                    last_round = Instant::now();
+                    for peer in self.live_peers() {
+                        peer.downloaded.sample(elapsed);
+                        peer.uploaded.sample(elapsed);
+                    }
+                    self.rechoke(store.is_complete(), round.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS));
+                    round += 1;
+                },
+                _ = self.rechoke.notified() => self.rechoke(store.is_complete(), false)
+            }
+        }
+    }
+
+    pub fn rechoke(&self, seeding : bool, rotate_optimistic : bool) {
+        let peers = self.live_peers();
+        let rate = |peer : &ChokedPeer| if seeding { peer.uploaded.rate() } else { peer.downloaded.rate() };
+
+        let mut candidates : Vec<&Arc<ChokedPeer>> = peers.iter()
+            // Snubbing only means something while we are downloading
+            .filter(|peer| peer.is_peer_interested() && (seeding || !peer.is_snubbing()))
+            .collect();
+        // Peers already unchoked keep their slot on a tie, so they don't flap every time someone else gets interested
+        candidates.sort_by_key(|peer| (std::cmp::Reverse(rate(peer)), !peer.is_unchoked()));
+        let regular : Vec<&Arc<ChokedPeer>> = candidates.into_iter().take(REGULAR_UNCHOKE_SLOTS).collect();
+
+        let mut optimistic = self.optimistic.lock().unwrap();
+        let current = optimistic.as_ref().and_then(Weak::upgrade)
+            .filter(|peer| peer.is_peer_interested() && !regular.iter().any(|regular| Arc::ptr_eq(regular, peer)));
+        *optimistic = match current {
+            Some(peer) if !rotate_optimistic => Some(Arc::downgrade(&peer)),
+            _ => Self::pick_optimistic(&peers, &regular).map(|peer| Arc::downgrade(&peer))
+        };
+        let optimistic = optimistic.as_ref().and_then(Weak::upgrade);
+
+        for peer in &peers {
+            let unchoked = regular.iter().any(|regular| Arc::ptr_eq(regular, peer))
+                || optimistic.as_ref().is_some_and(|optimistic| Arc::ptr_eq(optimistic, peer));
+            if peer.unchoked.swap(unchoked, Ordering::Relaxed) != unchoked {
+                peer.choke_changed.notify_one();
+            }
+        }
+    }
+
+    fn pick_optimistic(peers : &[Arc<ChokedPeer>], regular : &[&Arc<ChokedPeer>]) -> Option<Arc<ChokedPeer>> {
+        let mut weighted : Vec<&Arc<ChokedPeer>> = vec![];
+        for peer in peers.iter().filter(|peer| peer.is_peer_interested() && !regular.iter().any(|regular| Arc::ptr_eq(regular, peer))) {
+            let weight = if peer.connected_at.elapsed() < NEW_PEER_AGE { 3 } else { 1 };
+            weighted.extend(std::iter::repeat_n(peer, weight));
+        }
+        weighted.choose(&mut rand::thread_rng()).map(|peer| (*peer).clone())
+    }
+
+    fn live_peers(&self) -> Vec<Arc<ChokedPeer>> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|peer| peer.strong_count() > 0);
+        peers.iter().filter_map(Weak::upgrade).collect()
+    }
+}
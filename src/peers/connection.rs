@@ -0,0 +1,137 @@
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_util::codec::Framed;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::peers::handshake::Handshake;
+use crate::peers::peer_message::{PeerMessage, PeerMessageCodec};
+
+// How many messages can be queued in each direction before the sender has to wait
+const CHANNEL_CAPACITY : usize = 64;
+// Peers drop connections that stay silent for two minutes
+const KEEP_ALIVE_INTERVAL : Duration = Duration::from_secs(90);
+// How long connecting to a peer and waiting for its handshake may take, each
+pub const HANDSHAKE_TIMEOUT : Duration = Duration::from_secs(10);
+
+// A connection to a single peer. The socket itself is owned by a background task that
+// decodes incoming messages into `incoming` and encodes whatever is pushed into `outgoing`.
+pub struct PeerConnection {
+    pub peer_address : SocketAddr,
+    pub handshake : Handshake,
+    outgoing : mpsc::Sender<PeerMessage>,
+    incoming : mpsc::Receiver<PeerMessage>
+}
+
+impl PeerConnection {
+    pub async fn connect(peer_address : SocketAddr, info_hash : [u8; 20], peer_id : [u8; 20]) -> Result<Self, Error> {
+        Self::connect_with(peer_address, Handshake::new(info_hash, peer_id)).await
+    }
+
+    // Dial the peer with our own handshake, to announce more than the extension protocol
+    pub async fn connect_with(peer_address : SocketAddr, our_handshake : Handshake) -> Result<Self, Error> {
+        let timed_out = || Error::new(std::io::ErrorKind::TimedOut, format!("Peer {peer_address} did not answer in time"));
+        let mut stream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(peer_address)).await.map_err(|_| timed_out())??;
+        write_handshake(&mut stream, &our_handshake).await?;
+        let handshake = timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream)).await.map_err(|_| timed_out())??;
+        if handshake.info_hash != our_handshake.info_hash {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Peer {peer_address} answered with a different info hash"),
+            ));
+        }
+        Ok(Self::spawn(peer_address, stream, handshake))
+    }
+
+    // Hand an already handshaked socket over to a background task
+    pub fn spawn(peer_address : SocketAddr, stream : TcpStream, handshake : Handshake) -> Self {
+        let (outgoing_sender, outgoing_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (incoming_sender, incoming_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let framed = Framed::new(stream, PeerMessageCodec::new());
+        tokio::spawn(Self::run(framed, outgoing_receiver, incoming_sender));
+
+        PeerConnection {
+            peer_address,
+            handshake,
+            outgoing: outgoing_sender,
+            incoming: incoming_receiver
+        }
+    }
+
+    pub async fn send(&self, message : PeerMessage) -> Result<(), Error> {
+        self.outgoing.send(message).await.map_err(|_| Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            format!("Connection to peer {} is closed", self.peer_address),
+        ))
+    }
+
+    // Returns None once the peer has closed the connection
+    pub async fn receive(&mut self) -> Option<PeerMessage> {
+        self.incoming.recv().await
+    }
+
+    // Returns a message only if one is already waiting
+    pub fn try_receive(&mut self) -> Result<Option<PeerMessage>, Error> {
+        match self.incoming.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                format!("Peer {} closed the connection", self.peer_address),
+            ))
+        }
+    }
+
+    async fn run(mut framed : Framed<TcpStream, PeerMessageCodec>,
+                 mut outgoing : mpsc::Receiver<PeerMessage>,
+                 incoming : mpsc::Sender<PeerMessage>) {
+        let mut keep_alive = Instant::now() + KEEP_ALIVE_INTERVAL;
+        loop {
+            tokio::select! {
+                _ = sleep_until(keep_alive) => {
+                    // A keep-alive is a bare zero length prefix, which a PeerMessage can't express
+                    if framed.get_mut().write_all(&[0; 4]).await.is_err() {
+                        break;
+                    }
+                    keep_alive = Instant::now() + KEEP_ALIVE_INTERVAL;
+                },
+                message = framed.next() => {
+                    let Some(Ok(message)) = message else { break };
+                    if incoming.send(message).await.is_err() {
+                        break;
+                    }
+                },
+                message = outgoing.recv() => {
+                    let Some(message) = message else { break };
+                    if framed.send(message).await.is_err() {
+                        break;
+                    }
+                    keep_alive = Instant::now() + KEEP_ALIVE_INTERVAL;
+                }
+            }
+        }
+    }
+}
+
+// https://wiki.theory.org/BitTorrentSpecification#Handshake
+pub async fn write_handshake(stream : &mut TcpStream, handshake : &Handshake) -> Result<(), Error> {
+    stream.write_all(bytemuck::bytes_of(handshake)).await
+}
+
+pub async fn read_handshake(stream : &mut TcpStream) -> Result<Handshake, Error> {
+    let mut handshake_bytes : [u8; size_of::<Handshake>()] = [0; size_of::<Handshake>()];
+    stream.read_exact(&mut handshake_bytes).await?;
+    let handshake : Handshake = *bytemuck::from_bytes(&handshake_bytes);
+    if handshake.length != 19 || &handshake.protocol != b"BitTorrent protocol" {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Peer did not answer with a BitTorrent handshake",
+        ));
+    }
+    Ok(handshake)
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::io::Error;
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::peers::peer_message::{MessageID, PeerMessage};
+
+// https://www.bittorrent.org/beps/bep_0010.html
+pub const EXTENDED_HANDSHAKE_ID : u8 = 0;
+
+// Payload of a message with id 20, the first byte tells which extension it belongs to
+pub struct ExtendedMessage {
+    pub extension_id : u8,
+    pub payload : Vec<u8>
+}
+
+#[derive(Default)]
+pub struct ExtendedMessageDecoder {}
+#[derive(Default)]
+pub struct ExtendedMessageEncoder {}
+
+// Number of outstanding requests we accept from a single peer
+pub const REQUEST_QUEUE_SIZE : u32 = 250;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ExtendedHandshake {
+    // Extension names mapped to the message id the sender wants to receive them with, 0 disables it
+    #[serde(default)]
+    pub m : BTreeMap<String, u8>,
+    // Client name and version
+    pub v : Option<String>,
+    // Local TCP listen port
+    pub p : Option<u16>,
+    pub reqq : Option<u32>,
+    // Compact address of the receiving peer as seen by the sender
+    pub yourip : Option<ByteBuf>,
+    pub metadata_size : Option<u64>
+}
+
+// A handler for one extension, answers are returned as payloads and sent with the id the peer chose
+pub trait Extension : Send + Sync {
+    fn name(&self) -> &str;
+
+    // Add extension specific entries to the handshake we send
+    fn extend_handshake(&self, _handshake : &mut ExtendedHandshake) {}
+
+    // Called once the peer's handshake arrives, only if the peer supports this extension
+    fn on_handshake(&mut self, _handshake : &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(vec![])
+    }
+
+    fn on_message(&mut self, payload : &[u8]) -> Result<Vec<Vec<u8>>, Error>;
+}
+
+// The extensions plugged into a peer session, an extension's local message id is its position + 1
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions : Vec<Box<dyn Extension>>,
+    peer_handshake : Option<ExtendedHandshake>
+}
+
+impl ExtendedMessage {
+    pub fn new(extension_id : u8, payload : Vec<u8>) -> Self {
+        Self {
+            extension_id,
+            payload
+        }
+    }
+
+    pub fn from_peer_message(peer_message : &PeerMessage) -> Result<Self, Error> {
+        let payload = peer_message.payload.clone().unwrap_or_default();
+        let mut payload_raw_bytes = BytesMut::from(payload.as_slice());
+        ExtendedMessageDecoder::new().decode(&mut payload_raw_bytes)?.ok_or(Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Extended message without an extension id",
+        ))
+    }
+
+    pub fn into_peer_message(self) -> Result<PeerMessage, Error> {
+        let mut payload_raw_bytes : BytesMut = BytesMut::new();
+        ExtendedMessageEncoder::new().encode(self, &mut payload_raw_bytes)?;
+        PeerMessage::new(MessageID::Extended, Some(payload_raw_bytes.to_vec()))
+    }
+}
+
+impl ExtendedHandshake {
+    pub fn into_peer_message(self) -> Result<PeerMessage, Error> {
+        let payload = serde_bencode::to_bytes(&self).map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))?;
+        ExtendedMessage::new(EXTENDED_HANDSHAKE_ID, payload).into_peer_message()
+    }
+
+    pub fn from_payload(payload : &[u8]) -> Result<Self, Error> {
+        serde_bencode::from_bytes(payload).map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the id peers have to use to reach this extension
+    pub fn register(&mut self, extension : Box<dyn Extension>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    pub fn handshake(&self, peer_address : SocketAddr, listen_port : u16) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            m: self.extensions.iter().enumerate()
+                .map(|(index, extension)| (extension.name().to_string(), index as u8 + 1))
+                .collect(),
+            v: Some(format!("rusty-bittorrent {}", env!("CARGO_PKG_VERSION"))),
+            p: Some(listen_port),
+            reqq: Some(REQUEST_QUEUE_SIZE),
+            yourip: Some(match peer_address {
+                SocketAddr::V4(address) => ByteBuf::from(address.ip().octets().to_vec()),
+                SocketAddr::V6(address) => ByteBuf::from(address.ip().octets().to_vec())
+            }),
+            metadata_size: None
+        };
+        for extension in &self.extensions {
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    // Route an extended message to its handler, returns the messages to send back
+    pub fn handle(&mut self, peer_message : &PeerMessage) -> Result<Vec<PeerMessage>, Error> {
+        let extended_message = ExtendedMessage::from_peer_message(peer_message)?;
+        if extended_message.extension_id == EXTENDED_HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::from_payload(&extended_message.payload)?;
+            let mut replies : Vec<PeerMessage> = vec![];
+            for index in 0..self.extensions.len() {
+                if handshake.m.get(self.extensions[index].name()).is_some_and(|&id| id != 0) {
+                    let payloads = self.extensions[index].on_handshake(&handshake)?;
+                    replies.extend(self.wrap_payloads(&handshake, index, payloads)?);
+                }
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(replies);
+        }
+
+        // Unknown extensions and messages sent before the handshake are ignored
+        let index = extended_message.extension_id as usize - 1;
+        if index >= self.extensions.len() || self.peer_handshake.is_none() {
+            return Ok(vec![]);
+        }
+        let payloads = self.extensions[index].on_message(&extended_message.payload)?;
+        self.wrap_payloads(self.peer_handshake.as_ref().unwrap(), index, payloads)
+    }
+
+    fn wrap_payloads(&self, handshake : &ExtendedHandshake, index : usize, payloads : Vec<Vec<u8>>) -> Result<Vec<PeerMessage>, Error> {
+        let Some(&peer_extension_id) = handshake.m.get(self.extensions[index].name()) else {
+            return Ok(vec![]);
+        };
+        payloads.into_iter()
+            .map(|payload| ExtendedMessage::new(peer_extension_id, payload).into_peer_message())
+            .collect()
+    }
+}
+
+impl ExtendedMessageDecoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ExtendedMessageEncoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Decoder for ExtendedMessageDecoder {
+    type Item = ExtendedMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let extension_id = src[0];
+        src.advance(1);
+        let payload = src.to_vec();
+        src.advance(payload.len());
+
+        Ok(Some(ExtendedMessage::new(extension_id, payload)))
+    }
+}
+
+impl Encoder<ExtendedMessage> for ExtendedMessageEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: ExtendedMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(1 + item.payload.len());
+        dst.put_u8(item.extension_id);
+        dst.extend_from_slice(&item.payload);
+        Ok(())
+    }
+}
@@ -11,6 +11,13 @@ pub struct Handshake {
     pub peer_id : [u8; 20]
 }
 
+// Bit 20 from the right of the reserved bytes announces support for the extension protocol
+const EXTENSION_PROTOCOL_RESERVED : [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+// The 4th most significant bit of the last reserved byte announces support for v2 torrents
+// https://www.bittorrent.org/beps/bep_0052.html#upgrade-path
+const V2_RESERVED_BYTE : usize = 7;
+const V2_RESERVED_BIT : u8 = 0x10;
+
 unsafe impl bytemuck::Zeroable for Handshake {}
 unsafe impl Pod for Handshake {}
 
@@ -19,9 +26,25 @@ impl Handshake {
         Self {
             length: 19,
             protocol: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved: EXTENSION_PROTOCOL_RESERVED,
             info_hash,
             peer_id
         }
     }
+
+    // For torrents with a v2 swarm, `info_hash` being either of the info hashes of a hybrid torrent
+    pub fn new_v2(info_hash : [u8; 20], peer_id : [u8; 20]) -> Self {
+        let mut handshake = Self::new(info_hash, peer_id);
+        handshake.reserved[V2_RESERVED_BYTE] |= V2_RESERVED_BIT;
+        handshake
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[V2_RESERVED_BYTE] & V2_RESERVED_BIT != 0
+    }
+
+    // https://www.bittorrent.org/beps/bep_0010.html
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
 }
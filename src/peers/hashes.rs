@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::SocketAddr;
+
+use crate::metainfo::{proof_leads_to, Sha256Hash, V2File, MERKLE_BLOCK_SIZE};
+use crate::peers::{Handshake, PeerConnection, PeerSession};
+use crate::peers::extension::ExtensionRegistry;
+use crate::peers::peer_message::{MessageID, PeerMessage};
+
+// Peers answer hash requests of at most this many hashes
+// https://www.bittorrent.org/beps/bep_0052.html#hash-request
+const HASH_REQUEST_MAX_LENGTH : usize = 512;
+
+// Asks for `length` hashes of layer `base_layer` of the merkle tree of the file with `pieces_root`, from `index`,
+// along with the uncle hashes proving them up to `proof_layers` layers above. A hash reject has the same payload.
+// https://www.bittorrent.org/beps/bep_0052.html#hash-request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    pub pieces_root : Sha256Hash,
+    pub base_layer : u32,
+    pub index : u32,
+    pub length : u32,
+    pub proof_layers : u32
+}
+
+// The answer to a hash request, the requested hashes followed by the uncle hashes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub request : HashRequest,
+    pub hashes : Vec<Sha256Hash>
+}
+
+impl HashRequest {
+    pub fn size() -> usize {
+        32 + 4 * 4
+    }
+
+    pub fn from_bytes(payload : &[u8]) -> Result<Self, Error> {
+        if payload.len() < Self::size() {
+            return Err(Error::new(std::io::ErrorKind::InvalidData, "Hash request message is too short"));
+        }
+        let field = |offset : usize| u32::from_be_bytes(payload[offset..offset + 4].try_into().unwrap());
+        Ok(HashRequest {
+            pieces_root: payload[..32].try_into().unwrap(),
+            base_layer: field(32),
+            index: field(36),
+            length: field(40),
+            proof_layers: field(44)
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload : Vec<u8> = Vec::with_capacity(Self::size());
+        payload.extend_from_slice(&self.pieces_root);
+        for field in [self.base_layer, self.index, self.length, self.proof_layers] {
+            payload.extend_from_slice(&field.to_be_bytes());
+        }
+        payload
+    }
+
+    pub fn into_peer_message(self) -> Result<PeerMessage, Error> {
+        PeerMessage::new(MessageID::HashRequest, Some(self.to_bytes()))
+    }
+
+    pub fn reject(self) -> Result<PeerMessage, Error> {
+        PeerMessage::new(MessageID::HashReject, Some(self.to_bytes()))
+    }
+}
+
+impl Hashes {
+    pub fn from_bytes(payload : &[u8]) -> Result<Self, Error> {
+        let request = HashRequest::from_bytes(payload)?;
+        let hashes = &payload[HashRequest::size()..];
+        if !hashes.len().is_multiple_of(32) {
+            return Err(Error::new(std::io::ErrorKind::InvalidData, "Hashes message has a truncated hash"));
+        }
+        Ok(Hashes {
+            request,
+            hashes: hashes.chunks_exact(32).map(|hash| hash.try_into().unwrap()).collect()
+        })
+    }
+
+    pub fn into_peer_message(self) -> Result<PeerMessage, Error> {
+        let mut payload = self.request.to_bytes();
+        for hash in &self.hashes {
+            payload.extend_from_slice(hash);
+        }
+        PeerMessage::new(MessageID::Hashes, Some(payload))
+    }
+}
+
+// Ask a peer of the v2 swarm for the piece layers of `files`, each one checked against the file's pieces root.
+// Returns the piece layers by pieces root.
+pub async fn fetch_piece_layers_from_peer(peer_ip : SocketAddr, our_handshake : Handshake, listen_port : u16, files : Vec<V2File>, piece_length : u64)
+                                          -> Result<Vec<(Sha256Hash, Vec<Sha256Hash>)>, Box<dyn std::error::Error + Send + Sync>> {
+    let connection = PeerConnection::connect_with(peer_ip, our_handshake).await?;
+    if !connection.handshake.supports_v2() {
+        return Err(format!("Peer {peer_ip} does not support v2 torrents").into());
+    }
+    let mut session = PeerSession::new(connection, ExtensionRegistry::new());
+    session.start(listen_port).await?;
+
+    // The piece layer is `base_layer` layers above the blocks, asked for in chunks proven up to the root
+    let base_layer = (piece_length / MERKLE_BLOCK_SIZE).trailing_zeros();
+    let mut piece_layers : Vec<Vec<Sha256Hash>> = vec![];
+    let mut pending : HashMap<HashRequest, usize> = HashMap::new();
+    for (file_index, file) in files.iter().enumerate() {
+        let pieces_root = file.pieces_root.ok_or("A file without pieces root has no piece layer")?;
+        let piece_count = file.length.div_ceil(piece_length) as usize;
+        let layer_width = piece_count.next_power_of_two();
+        let length = layer_width.min(HASH_REQUEST_MAX_LENGTH);
+        for index in (0..piece_count).step_by(length) {
+            let request = HashRequest {
+                pieces_root,
+                base_layer,
+                index: index as u32,
+                length: length as u32,
+                proof_layers: (layer_width / length).trailing_zeros()
+            };
+            session.connection.send(request.into_peer_message()?).await?;
+            pending.insert(request, file_index);
+        }
+        piece_layers.push(vec![[0; 32]; piece_count]);
+    }
+
+    while !pending.is_empty() {
+        let peer_message = session.receive().await?;
+        let payload = peer_message.payload.as_deref().unwrap_or_default();
+        match peer_message.message_id {
+            MessageID::Hashes => {
+                let hashes = Hashes::from_bytes(payload)?;
+                let Some(file_index) = pending.remove(&hashes.request) else { continue };
+                let request = hashes.request;
+                let (index, length) = (request.index as usize, request.length as usize);
+                if !proof_leads_to(&request.pieces_root, index, length, &hashes.hashes) {
+                    return Err(format!("Peer {peer_ip} sent hashes that don't match the pieces root").into());
+                }
+                // The end of the last chunk is padding
+                let piece_layer = &mut piece_layers[file_index];
+                let count = length.min(piece_layer.len() - index);
+                piece_layer[index..index + count].copy_from_slice(&hashes.hashes[..count]);
+            },
+            MessageID::HashReject => return Err(format!("Peer {peer_ip} rejected our hash request").into()),
+            _ => {}
+        }
+    }
+    Ok(files.iter().filter_map(|file| file.pieces_root).zip(piece_layers).collect())
+}
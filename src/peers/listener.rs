@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::peers::{read_handshake, write_handshake, Handshake, PeerConnection, HANDSHAKE_TIMEOUT};
+
+// Ports tried in order when the preferred one is taken
+pub const LISTEN_PORT_RANGE : std::ops::RangeInclusive<u16> = 6881..=6889;
+// Told to trackers and peers when we don't listen on a port of our own
+pub const DEFAULT_PORT : u16 = 6882;
+// Incoming peers waiting for their torrent to pick them up
+const INCOMING_CAPACITY : usize = 16;
+// Accept errors such as running out of file descriptors tend to last, give them some time to go away
+const ACCEPT_RETRY_DELAY : Duration = Duration::from_millis(100);
+
+type Torrents = Arc<Mutex<HashMap<[u8; 20], mpsc::Sender<IncomingPeer>>>>;
+
+// A peer that connected to us and sent its handshake, we have not answered yet
+pub struct IncomingPeer {
+    pub peer_address : SocketAddr,
+    pub handshake : Handshake,
+    stream : TcpStream
+}
+
+impl IncomingPeer {
+    // Answer with our own handshake and start talking to the peer like any other
+    pub async fn accept(mut self, peer_id : [u8; 20], supports_v2 : bool) -> Result<PeerConnection, Error> {
+        let handshake = match supports_v2 {
+            true => Handshake::new_v2(self.handshake.info_hash, peer_id),
+            false => Handshake::new(self.handshake.info_hash, peer_id)
+        };
+        write_handshake(&mut self.stream, &handshake).await?;
+        Ok(PeerConnection::spawn(self.peer_address, self.stream, self.handshake))
+    }
+}
+
+// Accepts peer connections in the background and routes them to the torrent they ask for by info hash.
+// Connections for a torrent nobody registered are dropped.
+pub struct PeerListener {
+    port : u16,
+    torrents : Torrents,
+    tasks : Vec<JoinHandle<()>>
+}
+
+impl PeerListener {
+    // Listen on `preferred_port` when given and free, otherwise on the first free port of LISTEN_PORT_RANGE.
+    // The IPv6 socket usually takes IPv4 peers too, the IPv4 one is for systems where it doesn't or has no IPv6.
+    pub async fn bind(preferred_port : Option<u16>) -> Result<Self, Error> {
+        let mut last_error = Error::new(std::io::ErrorKind::AddrInUse, "No port to listen on");
+        for port in preferred_port.into_iter().chain(LISTEN_PORT_RANGE) {
+            let mut listeners : Vec<TcpListener> = vec![];
+            for address in ["::", "0.0.0.0"] {
+                match TcpListener::bind((address, port)).await {
+                    Ok(listener) => listeners.push(listener),
+                    Err(err) => last_error = err
+                }
+            }
+            if listeners.is_empty() {
+                continue;
+            }
+            let torrents : Torrents = Arc::new(Mutex::new(HashMap::new()));
+            let tasks = listeners.into_iter().map(|listener| tokio::spawn(Self::run(listener, torrents.clone()))).collect();
+            return Ok(PeerListener { port, torrents, tasks });
+        }
+        Err(last_error)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Peers asking for any of `info_hashes` are sent on the returned channel until it is dropped,
+    // a hybrid torrent takes peers from both its swarms
+    pub fn register(&self, info_hashes : &[[u8; 20]]) -> mpsc::Receiver<IncomingPeer> {
+        let (sender, receiver) = mpsc::channel(INCOMING_CAPACITY);
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in info_hashes {
+            torrents.insert(*info_hash, sender.clone());
+        }
+        receiver
+    }
+
+    async fn run(listener : TcpListener, torrents : Torrents) {
+        loop {
+            let (stream, peer_address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("Could not accept a peer connection: {err}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            // IPv4 peers reaching the IPv6 socket show up as mapped addresses
+            let peer_address = SocketAddr::new(peer_address.ip().to_canonical(), peer_address.port());
+            tokio::spawn(Self::route(stream, peer_address, torrents.clone()));
+        }
+    }
+
+    async fn route(mut stream : TcpStream, peer_address : SocketAddr, torrents : Torrents) {
+        let Ok(Ok(handshake)) = timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream)).await else { return };
+        let sender = torrents.lock().unwrap().get(&handshake.info_hash).cloned();
+        let Some(sender) = sender else { return };
+        if sender.send(IncomingPeer { peer_address, handshake, stream }).await.is_err() {
+            torrents.lock().unwrap().remove(&handshake.info_hash);
+        }
+    }
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::Error;
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+
+use crate::metainfo::{bencoded_value_length, Info, Magnet, TorrentMetaInfo};
+use crate::peers::{announce, AnnounceRequest, PeerConnection, PeerSession, PEER_ID};
+use crate::peers::extension::{ExtendedHandshake, Extension, ExtensionRegistry};
+
+// https://www.bittorrent.org/beps/bep_0009.html
+pub const UT_METADATA : &str = "ut_metadata";
+pub const METADATA_PIECE_SIZE : usize = 16 * 1024;
+// Refuse to allocate more than this for an info dictionary announced by a peer
+const METADATA_MAX_SIZE : u64 = 8 * 1024 * 1024;
+
+const METADATA_REQUEST : u8 = 0;
+const METADATA_DATA : u8 = 1;
+const METADATA_REJECT : u8 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetadataMessage {
+    pub msg_type : u8,
+    pub piece : u32,
+    pub total_size : Option<u64>
+}
+
+// Resolve a magnet link into a full metainfo by downloading the info dictionary from the swarm,
+// telling trackers and peers we listen on `listen_port`
+pub async fn fetch_metadata(magnet : &Magnet, listen_port : u16) -> Result<TorrentMetaInfo, Box<dyn std::error::Error>> {
+    let mut peers_ips = magnet.peers.clone();
+    let request = AnnounceRequest {
+        info_hash: magnet.info_hash,
+        peer_id: *PEER_ID,
+        port: listen_port,
+        uploaded: 0,
+        downloaded: 0,
+        // The size is unknown until we have the metadata, anything but 0 keeps us from being seen as a seeder
+        left: 1,
+        compact: true,
+        event: None,
+        tracker_id: None
+    };
+    for tracker in &magnet.trackers {
+        if let Ok(tracker_response) = announce(tracker, &request).await {
+            peers_ips.extend(tracker_response.peers());
+        }
+    }
+
+    let mut fetches = JoinSet::new();
+    for peer_ip in peers_ips {
+        fetches.spawn(fetch_metadata_from_peer(peer_ip, magnet.info_hash, listen_port));
+    }
+    while let Some(fetch) = fetches.join_next().await {
+        if let Ok(Ok(info_bytes)) = fetch {
+            fetches.abort_all();
+            let info = Info::from_bytes(&info_bytes)?;
+            if info.piece_length == 0 {
+                return Err("The torrent metadata has a piece length of 0".into());
+            }
+            if info.is_v2() && !info.pieces_roots_valid() {
+                return Err("The torrent metadata has a file without pieces root".into());
+            }
+            return Ok(magnet.to_metainfo(info));
+        }
+    }
+    Err("No peer could provide the torrent metadata".into())
+}
+
+// Ask a single peer for every piece of the info dictionary
+pub async fn fetch_metadata_from_peer(peer_ip : SocketAddr, info_hash : [u8; 20], listen_port : u16) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let connection = PeerConnection::connect(peer_ip, info_hash, *PEER_ID).await?;
+    if !connection.handshake.supports_extensions() {
+        return Err(format!("Peer {peer_ip} does not support the extension protocol").into());
+    }
+    let (extension, mut metadata_receiver) = MetadataExtension::fetch(info_hash);
+    let mut extensions = ExtensionRegistry::new();
+    extensions.register(Box::new(extension));
+    let mut session = PeerSession::new(connection, extensions);
+    session.start(listen_port).await?;
+
+    loop {
+        session.receive().await?;
+        if let Ok(metadata) = metadata_receiver.try_recv() {
+            return Ok(metadata);
+        }
+        if session.peer_extensions().is_some_and(|handshake| !handshake.m.contains_key(UT_METADATA)) {
+            return Err(format!("Peer {peer_ip} does not serve metadata").into());
+        }
+    }
+}
+
+// ut_metadata handler, either serves an info dictionary we have or downloads one we don't
+pub struct MetadataExtension {
+    info_hash : [u8; 20],
+    metadata : Vec<u8>,
+    received_pieces : Vec<bool>,
+    complete : bool,
+    sender : Option<oneshot::Sender<Vec<u8>>>
+}
+
+impl MetadataExtension {
+    pub fn serve(metadata : Vec<u8>) -> Self {
+        MetadataExtension {
+            info_hash: Sha1::digest(&metadata).into(),
+            metadata,
+            received_pieces: vec![],
+            complete: true,
+            sender: None
+        }
+    }
+
+    // The receiver gets the info dictionary once it has been checked against the info hash
+    pub fn fetch(info_hash : [u8; 20]) -> (Self, oneshot::Receiver<Vec<u8>>) {
+        let (sender, receiver) = oneshot::channel();
+        let extension = MetadataExtension {
+            info_hash,
+            metadata: vec![],
+            received_pieces: vec![],
+            complete: false,
+            sender: Some(sender)
+        };
+        (extension, receiver)
+    }
+
+    fn invalid_data(message : String) -> Error {
+        Error::new(std::io::ErrorKind::InvalidData, message)
+    }
+
+    fn encode(message : &MetadataMessage) -> Result<Vec<u8>, Error> {
+        serde_bencode::to_bytes(message).map_err(|err| Self::invalid_data(err.to_string()))
+    }
+
+    fn piece_range(&self, piece : usize) -> std::ops::Range<usize> {
+        let start = piece * METADATA_PIECE_SIZE;
+        start..(start + METADATA_PIECE_SIZE).min(self.metadata.len())
+    }
+
+    fn store_piece(&mut self, piece : usize, data : &[u8]) -> Result<(), Error> {
+        if piece >= self.received_pieces.len() {
+            return Err(Self::invalid_data(format!("Unknown metadata piece {piece}")));
+        }
+        let range = self.piece_range(piece);
+        if data.len() != range.len() {
+            return Err(Self::invalid_data(format!("Metadata piece {piece} has a wrong size")));
+        }
+        self.metadata[range].copy_from_slice(data);
+        self.received_pieces[piece] = true;
+
+        if self.received_pieces.iter().all(|&received| received) {
+            // v2 swarms go by the SHA-256 of the info dictionary, truncated
+            if Sha1::digest(&self.metadata).as_slice() != self.info_hash && Sha256::digest(&self.metadata)[..20] != self.info_hash {
+                return Err(Self::invalid_data("Metadata does not match the info hash".to_string()));
+            }
+            self.complete = true;
+            if let Some(sender) = self.sender.take() {
+                let _ = sender.send(self.metadata.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, handshake : &mut ExtendedHandshake) {
+        if self.complete {
+            handshake.metadata_size = Some(self.metadata.len() as u64);
+        }
+    }
+
+    fn on_handshake(&mut self, handshake : &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        if self.complete {
+            return Ok(vec![]);
+        }
+        let metadata_size = handshake.metadata_size.unwrap_or(0);
+        if metadata_size == 0 || metadata_size > METADATA_MAX_SIZE {
+            return Err(Self::invalid_data(format!("Invalid metadata size {metadata_size}")));
+        }
+        self.metadata = vec![0; metadata_size as usize];
+        self.received_pieces = vec![false; self.metadata.len().div_ceil(METADATA_PIECE_SIZE)];
+        (0..self.received_pieces.len())
+            .map(|piece| Self::encode(&MetadataMessage { msg_type: METADATA_REQUEST, piece: piece as u32, total_size: None }))
+            .collect()
+    }
+
+    fn on_message(&mut self, payload : &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let message : MetadataMessage = serde_bencode::from_bytes(payload).map_err(|err| Self::invalid_data(err.to_string()))?;
+        let piece = message.piece as usize;
+        match message.msg_type {
+            METADATA_REQUEST => {
+                if !self.complete || piece >= self.metadata.len().div_ceil(METADATA_PIECE_SIZE) {
+                    return Ok(vec![Self::encode(&MetadataMessage { msg_type: METADATA_REJECT, piece: message.piece, total_size: None })?]);
+                }
+                let mut reply = Self::encode(&MetadataMessage {
+                    msg_type: METADATA_DATA,
+                    piece: message.piece,
+                    total_size: Some(self.metadata.len() as u64)
+                })?;
+                reply.extend_from_slice(&self.metadata[self.piece_range(piece)]);
+                Ok(vec![reply])
+            },
+            METADATA_DATA if !self.complete => {
+                let dictionary_length = bencoded_value_length(payload).ok_or(Self::invalid_data("Invalid metadata message".to_string()))?;
+                self.store_piece(piece, &payload[dictionary_length..])?;
+                Ok(vec![])
+            },
+            METADATA_REJECT if !self.complete => Err(Self::invalid_data(format!("Peer rejected metadata piece {piece}"))),
+            _ => Ok(vec![])
+        }
+    }
+}
@@ -3,234 +3,671 @@ mod handshake;
 mod peer_message;
 mod request;
 mod piece;
+mod connection;
+mod session;
+mod scheduler;
+mod tracker;
+mod announcer;
+mod udp_tracker;
+mod scrape;
+mod extension;
+mod metadata;
+mod bitfield;
+mod piece_store;
+mod upload;
+mod listener;
+mod choker;
+mod pipeline;
+mod ban;
+mod resume;
+mod hashes;
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::collections::HashMap;
-use std::fs;
-use sha1::{Digest, Sha1};
-use tokio_util::bytes::BytesMut;
-use tokio_util::codec::{Decoder, Encoder};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 pub use tracker_response::*;
+pub use handshake::*;
+pub use peer_message::*;
+pub use request::*;
+pub use piece::*;
+pub use connection::*;
+pub use session::*;
+pub use scheduler::*;
+pub use tracker::*;
+pub use announcer::*;
+pub use udp_tracker::*;
+pub use scrape::*;
+pub use extension::*;
+pub use metadata::*;
+pub use bitfield::*;
+pub use piece_store::*;
+pub use upload::*;
+pub use listener::*;
+pub use choker::*;
+pub use pipeline::*;
+pub use ban::*;
+pub use resume::*;
+pub use hashes::*;
 
-use crate::metainfo::TorrentMetaInfo;
-use crate::peers::handshake::Handshake;
-use crate::peers::peer_message::{MessageID, PeerMessage, PeerMessageDecoder, PeerMessageEncoder};
-use crate::peers::piece::PieceDecoder;
-use crate::peers::request::{Request, RequestEncoder};
+use crate::metainfo::{FileLayout, MerkleTree, TorrentMetaInfo};
+use crate::storage::{verify, FileStorage, Storage, VerifyReport};
 
 pub const BLOCK_MAX : u64 = 16 * 1024;
+pub const PEER_ID : &[u8; 20] = b"13374313374313374369";
+const SCHEDULER_POLL_INTERVAL : Duration = Duration::from_millis(500);
+// Progress is saved this often, and when the download stops
+const RESUME_SAVE_INTERVAL : Duration = Duration::from_secs(60);
+// Workers get this long to put their partial pieces aside once the download stops
+const STOP_TIMEOUT : Duration = Duration::from_secs(5);
 
 pub struct Peers {
     metainfo : TorrentMetaInfo,
-    peer_id : String,
-    port : String, // 6881-6889
-    uploaded : u64,
-    downloaded : u64,
-    left : u64,
+    peer_id : [u8; 20],
+    port : u16, // 6881-6889
+    // Tried first when we start listening for peers
+    listen_port : Option<u16>,
+    stats : Arc<TransferStats>,
     compact : bool,
-    peers_connections : HashMap<String, TcpStream>,
-    pub pieces_hash : Vec<String>,
-    buffer : BytesMut
+    trackers : TrackerManager,
+    choker : Arc<Choker>,
+    smart_ban : Arc<SmartBan>,
+    download_directory : PathBuf,
+    // Files under download_directory unless set
+    storage : Option<Arc<dyn Storage>>,
+    // Opened once, ahead of the first announce when downloading from trackers
+    store : Option<Arc<PieceStore>>,
+    // Peers we knew in an earlier run, dialed first
+    saved_peers : Vec<SocketAddr>,
+    stop : CancellationToken,
+    peers_connections : HashMap<SocketAddr, PeerSession>,
+    // Served to v2 peers asking for hashes
+    merkle_trees : Arc<MerkleTrees>,
+    pub pieces_hash : Vec<String>
 }
 
 impl Peers {
     pub fn new(metainfo : TorrentMetaInfo) -> Self {
-        // ad-hoc: length is not available in all torrent files, so I try to deduce the length
-        let length = match metainfo.info.length {
-            None => (metainfo.info.pieces.len() / 20) as u64 * metainfo.info.piece_length,
-            Some(len) => len
-        };
+        let length = metainfo.info.total_length();
+        let (pieces_hash, merkle_trees) = Self::piece_hashes(&metainfo);
+
+        Peers {
+            trackers: TrackerManager::new(&metainfo),
+            metainfo,
+            peer_id: *PEER_ID,
+            port: DEFAULT_PORT,
+            listen_port: None,
+            stats: Arc::new(TransferStats::new(length)),
+            compact: true,
+            choker: Arc::new(Choker::new()),
+            smart_ban: Arc::new(SmartBan::new()),
+            download_directory: PathBuf::from("."),
+            storage: None,
+            store: None,
+            saved_peers: vec![],
+            stop: CancellationToken::new(),
+            peers_connections: HashMap::new(),
+            merkle_trees: Arc::new(merkle_trees),
+            pieces_hash
+        }
+    }
+
+    fn piece_hashes(metainfo : &TorrentMetaInfo) -> (Vec<String>, MerkleTrees) {
         let mut pieces_hash : Vec<String> = vec![];
         for index in (0..metainfo.info.pieces.len()).step_by(20) {
             let raw_hash = &metainfo.info.pieces[index..index + 20];
             pieces_hash.push(base16ct::lower::encode_string(raw_hash));
         }
+        // v2 only torrents have no SHA-1 pieces, hybrid torrents are checked against the SHA-1 ones
+        if !metainfo.info.is_v1() && metainfo.info.is_v2() {
+            pieces_hash = metainfo.v2_piece_hashes().iter().map(|hash| base16ct::lower::encode_string(hash)).collect();
+        }
+        let merkle_trees = metainfo.info.v2_files().iter()
+            .filter_map(|file| file.pieces_root)
+            .filter_map(|pieces_root| {
+                let piece_layer = metainfo.piece_layer(&pieces_root)?;
+                Some((pieces_root, MerkleTree::from_piece_layer(&piece_layer, metainfo.info.piece_length)))
+            })
+            .collect();
+        (pieces_hash, merkle_trees)
+    }
 
-        Peers {
-            metainfo,
-            peer_id: "13374313374313374369".to_string(),
-            port: "6882".to_string(),
-            uploaded: 0,
-            downloaded: 0,
-            left: length,
-            compact: true,
-            peers_connections: HashMap::new(),
-            pieces_hash,
-            buffer: BytesMut::new()
+    pub async fn discover(&mut self) -> Result<TrackerResponse, Box<dyn std::error::Error>> {
+        let request = self.announce_request();
+        match self.trackers.announce(&request).await {
+            Ok((_, tracker_response)) => Ok(tracker_response),
+            Err(err) => Err(err as Box<dyn std::error::Error>)
         }
     }
 
-    pub async fn discover(&self) -> Result<TrackerResponse, Box<dyn std::error::Error>> {
-        let url = self.construct_url();
-        let body = reqwest::get(url)
-            .await?
-            .bytes()
-            .await?;
-        let decoded_value : TrackerResponse = serde_bencode::from_bytes(&*body).expect("could not decode tracker response");
-        Ok(decoded_value)
+    // Ask every tracker how the swarm is doing, without joining it
+    pub async fn scrape(&self) -> Vec<(String, Result<ScrapeFile, Box<dyn std::error::Error + Send + Sync>>)> {
+        let info_hash = self.info_hash();
+        let mut results = vec![];
+        for tracker in self.trackers.tiers().iter().flatten() {
+            let result = match scrape(tracker, &[info_hash]).await {
+                Ok(mut files) => files.remove(&info_hash).ok_or_else(|| "Tracker does not know about this torrent".into()),
+                Err(err) => Err(err)
+            };
+            results.push((tracker.clone(), result));
+        }
+        results
+    }
+
+    fn announce_request(&self) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: self.info_hash(),
+            peer_id: self.peer_id,
+            port: self.port,
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left: self.stats.left(),
+            compact: self.compact,
+            event: None,
+            tracker_id: None
+        }
     }
 
     // https://wiki.theory.org/BitTorrentSpecification#Handshake
-    pub fn handshake(&mut self, peer_ip : &String) -> Result<Handshake, Box<dyn std::error::Error>> {
-        let handshake : Handshake = Handshake::new(<[u8; 20]>::try_from(self.metainfo.info.hash_raw()).unwrap(),
-                                                      <[u8; 20]>::try_from(self.peer_id.as_bytes()).unwrap());
-        let mut stream = TcpStream::connect(peer_ip)?;
-        let handshake_bytes = bytemuck::bytes_of(&handshake);
-        assert_eq!(handshake_bytes.len(), size_of::<Handshake>());
-        stream.write(handshake_bytes)?;
-
-        let mut peer_handshake_bytes: [u8; size_of::<Handshake>()] = [0; size_of::<Handshake>()];
-        let bytes_read = stream.read(&mut peer_handshake_bytes)?;
-        assert_eq!(bytes_read, size_of::<Handshake>());
-        let handshake : Handshake = *bytemuck::from_bytes(&peer_handshake_bytes);
-
-        self.peers_connections.insert(peer_ip.clone(), stream);
+    pub async fn handshake(&mut self, peer_ip : SocketAddr) -> Result<Handshake, Box<dyn std::error::Error>> {
+        let connection = PeerConnection::connect_with(peer_ip, self.our_handshake(self.info_hash())).await?;
+        let handshake = connection.handshake;
+        let mut session = PeerSession::new(connection, self.extensions());
+        session.start(self.port).await.map_err(|err| err as Box<dyn std::error::Error>)?;
+        self.peers_connections.insert(peer_ip, session);
         Ok(handshake)
     }
 
-    pub fn download_piece(&mut self, peer_ip : &String, piece_index : usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    pub async fn download_piece(&mut self, peer_ip : SocketAddr, piece_index : usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         assert!(piece_index < self.pieces_hash.len());
+        let piece_length = self.piece_length(piece_index)?;
+
+        // Don't try to handshake a peer if we already established a connexion
+        if !self.peers_connections.contains_key(&peer_ip) {
+            self.handshake(peer_ip).await?;
+        }
+        let session = self.peers_connections.get_mut(&peer_ip).unwrap();
+        session.download_piece(piece_index, piece_length).await.map_err(|err| err as Box<dyn std::error::Error>)
+    }
+
+    pub async fn download(&mut self, peer_ip : SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        self.download_swarm(&[peer_ip], None, None, std::future::ready(())).await
+    }
 
-        let piece_length = if piece_index == (self.pieces_hash.len() - 1) {
-            match self.metainfo.info.length.unwrap() % self.metainfo.info.piece_length {
-                0 => self.metainfo.info.piece_length,
-                len => len,
+    // Download with peers from the trackers, which are kept informed of our progress the whole time,
+    // and with the peers that connect to us. Once the download is complete we keep seeding until `seed_until` resolves.
+    pub async fn download_torrent<F : Future<Output = ()>>(&mut self, seed_until : F) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = match PeerListener::bind(self.listen_port).await {
+            Ok(listener) => {
+                self.port = listener.port();
+                Some(listener)
+            },
+            Err(err) => {
+                eprintln!("Could not listen for incoming peers: {err}");
+                None
             }
-        } else {
-            self.metainfo.info.piece_length
         };
+        // v2 only torrents from a magnet link come without piece layers, their pieces can't be checked until we get them
+        let mut peers_ips = vec![];
+        if !self.metainfo.info.is_v1() && !self.metainfo.missing_piece_layers().is_empty() {
+            peers_ips = self.fetch_piece_layers().await?;
+        }
+        // Before the first announce, so it reports what an earlier run already downloaded
+        self.open_store().await?;
+        let info_hashes = self.metainfo.info.info_hashes();
+        let incoming_peers = listener.as_ref().map(|listener| listener.register(&info_hashes));
+        let mut announcer = Announcer::spawn(self.trackers.clone(), self.announce_request(), info_hashes[1..].to_vec(), self.stats.clone());
+        let result = self.download_swarm(&peers_ips, Some(&mut announcer), incoming_peers, seed_until).await;
+        announcer.stop().await;
+        result
+    }
 
-        // Don't try to handshake a peer if we already established a connexion
-        let mut tcp_stream : &TcpStream = match self.peers_connections.get(peer_ip) {
-            None => {
-                self.handshake(peer_ip)?;
-                self.peers_connections.get(peer_ip).unwrap()
-            },
-            Some(stream) => {
-                // We already did a handshake with this peer, go ahead and send a piece request
-                self.send_piece_request(peer_ip, piece_index, piece_length)?;
-                stream
+    // Ask the peers from the trackers for the piece layers we miss, returns the peers so the download can start with them
+    async fn fetch_piece_layers(&mut self) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+        let peers_ips = self.discover().await?.peers();
+        let files = self.metainfo.missing_piece_layers();
+        let mut fetches = JoinSet::new();
+        for peer_ip in &peers_ips {
+            let our_handshake = self.our_handshake(self.info_hash());
+            fetches.spawn(fetch_piece_layers_from_peer(*peer_ip, our_handshake, self.port, files.clone(), self.metainfo.info.piece_length));
+        }
+        let stop = self.stop.clone();
+        let piece_layers = loop {
+            tokio::select! {
+                fetch = fetches.join_next() => match fetch {
+                    Some(Ok(Ok(piece_layers))) => break piece_layers,
+                    Some(_) => {},
+                    None => return Err("No peer could provide the piece layers".into())
+                },
+                _ = stop.cancelled() => return Err("Download stopped before getting the piece layers".into())
             }
         };
+        for (pieces_root, piece_layer) in &piece_layers {
+            self.metainfo.add_piece_layer(pieces_root, piece_layer);
+        }
+        let (pieces_hash, merkle_trees) = Self::piece_hashes(&self.metainfo);
+        self.pieces_hash = pieces_hash;
+        self.merkle_trees = Arc::new(merkle_trees);
+        Ok(peers_ips)
+    }
+
+    pub fn set_listen_port(&mut self, port : u16) {
+        self.listen_port = Some(port);
+    }
 
+    // Where the files of the torrent and the resume data go, the current directory by default
+    pub fn set_download_directory(&mut self, download_directory : PathBuf) {
+        self.download_directory = download_directory;
+    }
+
+    // Keep the torrent's data in `storage` instead of files, it has to be laid out for this torrent's info.
+    // Progress is only saved between runs for the files.
+    pub fn set_storage(&mut self, storage : Arc<dyn Storage>) {
+        self.storage = Some(storage);
+    }
+
+    pub fn stats(&self) -> Arc<TransferStats> {
+        self.stats.clone()
+    }
+
+    // Check the data already there against the piece hashes. A download started afterwards only fetches
+    // the pieces that failed, whatever the resume data said.
+    pub async fn verify(&mut self) -> Result<VerifyReport, Box<dyn std::error::Error>> {
+        let store = self.open_store().await?;
+        let pieces_hash = self.pieces_hash.clone();
+        let report = tokio::task::spawn_blocking({
+            let store = store.clone();
+            move || -> Result<VerifyReport, std::io::Error> {
+                store.flush()?;
+                Ok(verify(store.storage().as_ref(), &pieces_hash))
+            }
+        }).await??;
+        let layout = store.layout();
+        let have = report.bitfield();
+        let have_length : u64 = have.pieces().map(|piece_index| layout.piece_size(piece_index)).sum();
+        self.stats.set_left(layout.total_length - have_length);
+        store.restore(have, HashMap::new());
+        Ok(report)
+    }
 
-        let mut downloaded_piece_data : Vec<u8> = vec![0; piece_length as usize];
-        let mut received_data : usize = 0;
+    // Cancelling it stops the download or the seeding, progress is saved for the next run
+    pub fn stop_token(&self) -> CancellationToken {
+        self.stop.clone()
+    }
+
+    // Connect to every peer at once, each one downloads whatever piece the scheduler hands out next
+    // and uploads whatever we have to its peer. Peers found by the announcer or connecting to us join as they come.
+    pub async fn download_swarm<F : Future<Output = ()>>(&mut self, peers_ips : &[SocketAddr], mut announcer : Option<&mut Announcer>,
+                                                         mut incoming_peers : Option<mpsc::Receiver<IncomingPeer>>,
+                                                         seed_until : F) -> Result<(), Box<dyn std::error::Error>> {
+        let store = self.open_store().await?;
+        let length = store.layout().total_length;
+        let mut scheduler = PieceScheduler::new(self.pieces_hash.clone(), self.metainfo.info.piece_length, length);
+        scheduler.restore(&store.bitfield());
+        let scheduler = Arc::new(Mutex::new(scheduler));
+        let (piece_sender, mut piece_receiver) = mpsc::channel::<(usize, Vec<u8>)>(peers_ips.len().max(8));
+        let mut piece_sender = Some(piece_sender);
+        let mut workers : JoinSet<()> = JoinSet::new();
+        let mut known_peers : HashSet<SocketAddr> = HashSet::new();
+        // Tells the workers to wrap up once we are done
+        let stopping = self.stop.child_token();
+        // Aborted along with the download
+        let mut background : JoinSet<()> = JoinSet::new();
+        background.spawn(self.choker.clone().run(store.clone()));
+        let saved_peers = std::mem::take(&mut self.saved_peers);
+        for peers_ips in [peers_ips, &saved_peers] {
+            self.spawn_swarm_workers(peers_ips, self.info_hash(), &mut known_peers, &mut workers, &scheduler, &store, piece_sender.as_ref().unwrap(), &stopping);
+        }
+
+        let mut seed_until = std::pin::pin!(seed_until);
+        let mut save_resume = tokio::time::interval_at(tokio::time::Instant::now() + RESUME_SAVE_INTERVAL, RESUME_SAVE_INTERVAL);
+        let mut received_pieces = store.bitfield().count();
         loop {
-            // Try to decode all the bytes in the buffer if you can
-            loop {
-                let peer_message = PeerMessageDecoder::new().decode(&mut self.buffer)?;
-                match peer_message {
-                    None => break,
-                    Some(peer_message) => {
-                        match peer_message.message_id {
-                            MessageID::Bitfield => {
-                                let peer_message = PeerMessage::new(MessageID::Interested, None)?;
-                                let mut buffer : BytesMut = BytesMut::new();
-                                let _ = PeerMessageEncoder::new().encode(peer_message, &mut buffer)?;
-                                tcp_stream.write(buffer.as_ref())?;
-                            },
-                            MessageID::UnChoke => {
-                                self.send_piece_request(&peer_ip, piece_index, piece_length)?;
-                            },
-                            MessageID::Piece => {
-                                let payload = peer_message.payload.expect("Piece message should have a payload");
-                                let mut payload_raw_bytes = BytesMut::from(payload.as_slice());
-                                let piece = PieceDecoder::new().decode(&mut payload_raw_bytes)?.expect("Failed decoding payload");
-                                assert_eq!(piece.index as usize, piece_index);
-                                downloaded_piece_data.splice(piece.begin as usize..piece.begin as usize + piece.block.len(), piece.block.clone());
-                                received_data += piece.block.len();
-
-                                if received_data == piece_length as usize {
-                                    return Ok(downloaded_piece_data);
-                                }
-                            },
-                            _ => panic!("panic")
+            let complete = received_pieces == self.pieces_hash.len();
+            if !complete && workers.is_empty() && announcer.is_none() && incoming_peers.is_none() {
+                // Nobody is left to download, drain what was already delivered
+                piece_sender = None;
+            }
+            tokio::select! {
+                piece = piece_receiver.recv(), if !complete => {
+                    let Some((piece_index, piece_data)) = piece else { break };
+                    if let Err(err) = self.write_piece(&store, piece_index, piece_data).await {
+                        // A disk error costs the piece, not the download
+                        eprintln!("Could not write piece {piece_index}: {err}");
+                        scheduler.lock().unwrap().retry(piece_index);
+                        continue;
+                    }
+                    received_pieces += 1;
+                    if received_pieces == self.pieces_hash.len() {
+                        Self::flush(&store).await?;
+                        self.save_resume(&store, &known_peers).await;
+                        println!("Wrote {} bytes to '{}'", length, self.metainfo.info.name);
+                        if let Some(announcer) = &announcer {
+                            announcer.completed().await;
                         }
                     }
-                }
+                },
+                peers = Self::announced_peers(&mut announcer) => match peers {
+                    Some(swarm_peers) => if let Some(piece_sender) = &piece_sender {
+                        self.spawn_swarm_workers(&swarm_peers.peers, swarm_peers.info_hash, &mut known_peers, &mut workers, &scheduler, &store,
+                                                 piece_sender, &stopping);
+                    },
+                    None => announcer = None
+                },
+                peer = Self::incoming_peer(&mut incoming_peers) => match peer {
+                    Some(peer) => if let Some(piece_sender) = piece_sender.as_ref().filter(|_| !self.smart_ban.is_banned(peer.peer_address.ip())) {
+                        self.spawn_swarm_worker(peer.accept(self.peer_id, self.metainfo.info.is_v2()), &mut workers, &scheduler, &store, piece_sender, &stopping);
+                    },
+                    None => incoming_peers = None
+                },
+                Some(_) = workers.join_next(), if !workers.is_empty() => {},
+                _ = save_resume.tick() => self.save_resume(&store, &known_peers).await,
+                _ = self.stop.cancelled() => break,
+                _ = &mut seed_until, if complete => break
             }
+        }
 
-            let mut stream_buffer : [u8; 1024] = [0; 1024];
-            let mut received_no_data_counter = 0;
-            loop {
-                let bytes_read = tcp_stream.read(&mut stream_buffer)?;
-                if bytes_read > 0 {
-                    self.buffer.extend_from_slice(&stream_buffer[..bytes_read]);
-                    break;
-                } else {
-                    received_no_data_counter += 1;
-                    if received_no_data_counter == 5 {
-                        panic!("Stopped receiving data")
-                    }
+        // Let the workers put what they got of their pieces aside, then keep whatever was delivered meanwhile
+        stopping.cancel();
+        let _ = tokio::time::timeout(STOP_TIMEOUT, async {
+            while workers.join_next().await.is_some() {}
+        }).await;
+        while let Ok((piece_index, piece_data)) = piece_receiver.try_recv() {
+            if !store.has_piece(piece_index) {
+                match self.write_piece(&store, piece_index, piece_data).await {
+                    Ok(()) => received_pieces += 1,
+                    Err(err) => eprintln!("Could not write piece {piece_index}: {err}")
                 }
             }
         }
+        Self::flush(&store).await?;
+        self.save_resume(&store, &known_peers).await;
+        if received_pieces < self.pieces_hash.len() {
+            return Err(format!("Download stopped with {received_pieces} of {} pieces", self.pieces_hash.len()).into());
+        }
+        Ok(())
     }
 
-    pub fn download(&mut self, peer_ip : &String) -> Result<(), Box<dyn std::error::Error>> {
-        let mut torrent_data : Vec<u8> = vec![0; self.metainfo.info.length.unwrap() as usize];
-        for piece in 0..self.pieces_hash.len() {
-            let piece_data = self.download_piece(peer_ip, piece)?;
-            let hash = Sha1::digest(&piece_data);
-            assert_eq!(base16ct::lower::encode_string(&hash), self.pieces_hash[piece]);
-            let piece_start = piece * BLOCK_MAX as usize;
-            torrent_data.splice(piece_start..piece_start + piece_data.len(), piece_data);
-        }
-        // Assume single file
-        let file_path = &self.metainfo.info.name;
-        Peers::write_raw_bytes_to_file(file_path, &torrent_data);
-        println!("Wrote {} bytes to '{}'", torrent_data.len(), file_path);
+    // Off the async workers, a write that fills the write cache flushes all of it to disk
+    async fn write_piece(&self, store : &Arc<PieceStore>, piece_index : usize, piece_data : Vec<u8>) -> Result<(), std::io::Error> {
+        let length = piece_data.len() as u64;
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.write_piece(piece_index, &piece_data)).await.map_err(std::io::Error::other)??;
+        self.stats.add_downloaded(length);
+        self.stats.set_left(self.stats.left().saturating_sub(length));
         Ok(())
     }
 
-    fn send_piece_request(&self, peer_ip : &String, piece_index : usize, piece_length : u64) -> Result<(), Box<dyn std::error::Error>> {
-        let mut tcp_stream : &TcpStream = self.peers_connections.get(peer_ip).expect(format!("Not connected to peer with ip {peer_ip}").as_str());
-        let blocks = (piece_length + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        for block in 0..blocks {
-            let block_length;
-            if block == blocks - 1 {
-                block_length = piece_length - (block * BLOCK_MAX);
-            } else {
-                block_length = BLOCK_MAX;
+    async fn flush(store : &Arc<PieceStore>) -> Result<(), std::io::Error> {
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.flush()).await.map_err(std::io::Error::other)?
+    }
+
+    // The storage of the torrent, with whatever an earlier run saved in the resume file restored.
+    // Creating the files and checking them against the resume data is left to the blocking pool.
+    async fn open_store(&mut self) -> Result<Arc<PieceStore>, Box<dyn std::error::Error>> {
+        if let Some(store) = &self.store {
+            return Ok(store.clone());
+        }
+        let store = match &self.storage {
+            Some(storage) => Arc::new(PieceStore::new(storage.clone())),
+            None => {
+                let layout = FileLayout::new(&self.metainfo.info)?;
+                let (info_hash, resume_path, download_directory) = (self.info_hash(), self.resume_path(), self.download_directory.clone());
+                let pieces_hash = self.pieces_hash.clone();
+                let (store, resume) = tokio::task::spawn_blocking(move || -> Result<_, std::io::Error> {
+                    let resume = ResumeData::load(&resume_path).filter(|resume| resume.is_for(info_hash));
+                    // Checked before creating the files, that could touch them
+                    let files_unchanged = resume.as_ref().is_some_and(|resume| resume.files_unchanged(&layout, &download_directory));
+                    let store = Arc::new(PieceStore::new(Arc::new(FileStorage::create(layout, &download_directory)?)));
+                    let resume = resume.and_then(|resume| {
+                        let (have, partial) = Self::restored_pieces(&store, &resume, files_unchanged, &pieces_hash)?;
+                        Some((resume, have, partial))
+                    });
+                    Ok((store, resume))
+                }).await??;
+                if let Some((resume, have, partial)) = resume {
+                    self.restore(&store, resume, have, partial);
+                }
+                store
             }
-            let piece = Request::new(piece_index as u32, (block * BLOCK_MAX) as u32, block_length as u32);
-            let mut piece_bytes : BytesMut = BytesMut::new();
-            RequestEncoder::new().encode(piece, &mut piece_bytes)?;
+        };
+        self.store = Some(store.clone());
+        Ok(store)
+    }
 
-            let request_message_peer = PeerMessage::new(MessageID::Request, Some(piece_bytes.to_vec()))?;
-            let mut request_message_peer_bytes : BytesMut = BytesMut::new();
-            PeerMessageEncoder::new().encode(request_message_peer, &mut request_message_peer_bytes)?;
+    // The pieces and partial blocks the resume data says we have, rechecked against their hash when the files changed
+    fn restored_pieces(store : &PieceStore, resume : &ResumeData, files_unchanged : bool, pieces_hash : &[String])
+                       -> Option<(Bitfield, HashMap<usize, Vec<u64>>)> {
+        let layout = store.layout();
+        let have = resume.have(layout.piece_count())?;
+        if files_unchanged {
+            return Some((have, resume.partial_blocks(layout)));
+        }
+        // The files changed since, only the pieces that still match their hash are kept
+        let mut verified = Bitfield::new(layout.piece_count());
+        for piece_index in have.pieces() {
+            let hash = store.storage().hash_piece(piece_index);
+            if hash.is_ok_and(|hash| base16ct::lower::encode_string(&hash) == pieces_hash[piece_index]) {
+                verified.set(piece_index);
+            }
+        }
+        Some((verified, HashMap::new()))
+    }
+
+    fn restore(&mut self, store : &PieceStore, resume : ResumeData, have : Bitfield, partial : HashMap<usize, Vec<u64>>) {
+        let layout = store.layout();
+        let have_length : u64 = have.pieces().map(|piece_index| layout.piece_size(piece_index)).sum();
+        self.stats.add_uploaded(resume.uploaded);
+        self.stats.add_downloaded(resume.downloaded);
+        self.stats.set_left(layout.total_length - have_length);
+        self.saved_peers = resume.peers();
+        println!("Resuming with {} of {} pieces", have.count(), layout.piece_count());
+        store.restore(have, partial);
+    }
 
-            tcp_stream.write(request_message_peer_bytes.as_ref())?;
+    // On the blocking pool, along with the flush it needs first
+    async fn save_resume(&self, store : &Arc<PieceStore>, known_peers : &HashSet<SocketAddr>) {
+        if self.storage.is_some() {
+            return;
+        }
+        let store = store.clone();
+        let (info_hash, resume_path, download_directory, stats) = (self.info_hash(), self.resume_path(), self.download_directory.clone(), self.stats.clone());
+        let known_peers : Vec<SocketAddr> = known_peers.iter().copied().collect();
+        let saved = tokio::task::spawn_blocking(move || {
+            // The bitfield counts the pieces still in the write cache, they have to be on disk before it's saved
+            store.flush()?;
+            ResumeData::new(info_hash, &store.bitfield(), &store.partial_blocks(), store.layout(), &download_directory, &stats, &known_peers)?
+                .save(&resume_path)
+        }).await;
+        match saved {
+            Ok(Ok(())) => {},
+            Ok(Err(err)) => eprintln!("Could not save resume data: {err}"),
+            Err(err) => eprintln!("Could not save resume data: {err}")
         }
-        Ok(())
     }
 
-    fn write_raw_bytes_to_file(file_path : &String, raw_data : &Vec<u8>) {
-        let mut file = fs::File::create(file_path).expect("Could not create file for piece");
-        file.write_all(raw_data.as_slice()).expect("Could not write to piece file");
+    fn resume_path(&self) -> PathBuf {
+        self.download_directory.join(format!("{}.resume", base16ct::lower::encode_string(&self.info_hash())))
     }
 
-    fn urlencode<T: ToString>(data : &T) -> String {
-        urlencoding::encode(&data.to_string()).to_string()
+    #[allow(clippy::too_many_arguments)]
+    // `info_hash` is the one of the swarm the peers come from, a hybrid torrent has two
+    fn spawn_swarm_workers(&self, peers_ips : &[SocketAddr], info_hash : [u8; 20], known_peers : &mut HashSet<SocketAddr>, workers : &mut JoinSet<()>,
+                           scheduler : &Arc<Mutex<PieceScheduler>>, store : &Arc<PieceStore>, piece_sender : &mpsc::Sender<(usize, Vec<u8>)>,
+                           stopping : &CancellationToken) {
+        for peer_ip in peers_ips {
+            if !self.smart_ban.is_banned(peer_ip.ip()) && known_peers.insert(*peer_ip) {
+                self.spawn_swarm_worker(PeerConnection::connect_with(*peer_ip, self.our_handshake(info_hash)), workers, scheduler, store,
+                                        piece_sender, stopping);
+            }
+        }
     }
 
-    fn construct_url(&self) -> String {
-        let tracker_url = self.metainfo.announce.clone();
-        let urlencoded_bencoded_info_hash = self.metainfo.urlencode_info_hash();
-        let urlencoded_peer_id = Self::urlencode(&self.peer_id);
-        let urlencoded_port = Self::urlencode(&self.port);
-        let urlencoded_uploaded = Self::urlencode(&self.uploaded);
-        let urlencoded_downloaded = Self::urlencode(&self.downloaded);
-        let urlencoded_left = Self::urlencode(&self.left);
-        let urlencoded_compact = match self.compact {
-            true => "1".to_string(),
-            false => "0".to_string()
+    // `connection` either dials the peer or answers a peer that dialed us
+    fn spawn_swarm_worker<C>(&self, connection : C, workers : &mut JoinSet<()>, scheduler : &Arc<Mutex<PieceScheduler>>,
+                             store : &Arc<PieceStore>, piece_sender : &mpsc::Sender<(usize, Vec<u8>)>, stopping : &CancellationToken)
+        where C: Future<Output = Result<PeerConnection, std::io::Error>> + Send + 'static {
+        let uploader = Uploader::new(store.clone(), self.choker.register(), self.stats.clone()).with_merkle_trees(self.merkle_trees.clone());
+        workers.spawn(Self::swarm_worker(connection, self.port, self.extensions(), uploader, scheduler.clone(), piece_sender.clone(),
+                                         self.smart_ban.clone(), stopping.clone()));
+    }
+
+    async fn incoming_peer(incoming_peers : &mut Option<mpsc::Receiver<IncomingPeer>>) -> Option<IncomingPeer> {
+        match incoming_peers {
+            Some(incoming_peers) => incoming_peers.recv().await,
+            None => std::future::pending().await
+        }
+    }
+
+    async fn announced_peers(announcer : &mut Option<&mut Announcer>) -> Option<SwarmPeers> {
+        match announcer {
+            Some(announcer) => announcer.peers().await,
+            None => std::future::pending().await
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn swarm_worker<C>(connection : C, listen_port : u16, extensions : ExtensionRegistry, uploader : Uploader,
+                             scheduler : Arc<Mutex<PieceScheduler>>, piece_sender : mpsc::Sender<(usize, Vec<u8>)>, smart_ban : Arc<SmartBan>,
+                             stopping : CancellationToken)
+        where C: Future<Output = Result<PeerConnection, std::io::Error>> {
+        let store = uploader.store().clone();
+        let mut session = match connection.await {
+            Ok(connection) => PeerSession::new(connection, extensions).with_uploader(uploader),
+            Err(_) => return
+        };
+        if session.start(listen_port).await.is_err() {
+            return;
+        }
+        // The peer's pieces counted in the swarm availability, taken back out when it goes away
+        let mut counted = Bitfield::new(session.peer_pieces().unwrap().piece_count());
+        let downloaded = tokio::select! {
+            downloaded = Self::download_from_peer(&mut session, &scheduler, &store, &piece_sender, &smart_ban, &mut counted) => downloaded,
+            _ = stopping.cancelled() => Err("Download stopped".into())
         };
-        tracker_url + "?info_hash=" + &*urlencoded_bencoded_info_hash + "&peer_id=" + &*urlencoded_peer_id
-            + "&port=" + &*urlencoded_port + "&uploaded=" + &*urlencoded_uploaded + "&downloaded=" + &*urlencoded_downloaded
-            + "&left=" + &*urlencoded_left + "&compact=" + &*urlencoded_compact
+        {
+            // Whatever was still queued on this peer goes back to the others, with the blocks we got of it.
+            // Under the scheduler's lock so nothing is written over a piece another peer just completed.
+            let mut scheduler = scheduler.lock().unwrap();
+            for (piece_index, blocks) in session.partial_pieces() {
+                if !scheduler.is_complete(piece_index) {
+                    let _ = store.write_partial(piece_index, &blocks);
+                }
+            }
+            for piece_index in session.queued_pieces() {
+                scheduler.release(piece_index);
+            }
+            scheduler.remove_peer_pieces(&counted);
+        }
+        if downloaded.is_ok() {
+            // Nothing left to download, the peer may still want what we have
+            tokio::select! {
+                _ = session.serve() => {},
+                _ = stopping.cancelled() => {}
+            }
+        }
+    }
+
+    // Download pieces from the peer until the scheduler is done, fails when the peer goes away
+    async fn download_from_peer(session : &mut PeerSession, scheduler : &Mutex<PieceScheduler>, store : &PieceStore,
+                                piece_sender : &mpsc::Sender<(usize, Vec<u8>)>, smart_ban : &SmartBan, counted : &mut Bitfield)
+                                -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let peer_ip = session.connection.peer_address.ip();
+        let mut hashes : HashMap<usize, String> = HashMap::new();
+        let mut peer_pieces = session.peer_pieces().unwrap().clone();
+        loop {
+            if smart_ban.is_banned(peer_ip) {
+                return Err(format!("Peer {peer_ip} is banned for sending corrupt data").into());
+            }
+            // Keep the session's request window full, pieces come in as blocks arrive
+            let new_pieces = session.take_new_pieces();
+            if !new_pieces.is_empty() {
+                peer_pieces = session.peer_pieces().unwrap().clone();
+            }
+            let completed_elsewhere : Vec<usize> = {
+                let mut scheduler = scheduler.lock().unwrap();
+                scheduler.add_peer_pieces(counted, &new_pieces);
+                while session.wants_piece() {
+                    let Some(piece) = scheduler.next_piece(&peer_pieces, &session.queued_pieces()) else { break };
+                    // Blocks a peer that went away already sent us are not asked for again
+                    session.add_piece(piece.blocks, store.take_partial(piece.index));
+                    hashes.insert(piece.index, piece.hash);
+                }
+                hashes.keys().copied().filter(|&piece_index| scheduler.is_complete(piece_index)).collect()
+            };
+            // Endgame: another peer delivered a piece we were also downloading
+            for piece_index in completed_elsewhere {
+                hashes.remove(&piece_index);
+                session.cancel_piece(piece_index).await?;
+            }
+            if hashes.is_empty() {
+                if scheduler.lock().unwrap().is_done() {
+                    return Ok(());
+                }
+                // The peer has nothing we need right now or everything left is being downloaded by other peers,
+                // stay around in case the peer gets new pieces or one of the others drops
+                session.wait(SCHEDULER_POLL_INTERVAL).await?;
+                continue;
+            }
+            let Some(piece) = session.receive_blocks().await? else { continue };
+            let hash = hashes.remove(&piece.index).unwrap_or_default();
+            if base16ct::lower::encode_string(&store.layout().piece_hash(piece.index, &piece.data)) != hash {
+                // Discarded and downloaded again, the peers that sent its blocks are suspects
+                scheduler.lock().unwrap().failed(piece.index);
+                Self::report_banned(smart_ban.hash_failed(piece.index, &piece.data, &piece.contributors));
+                continue;
+            }
+            Self::report_banned(smart_ban.piece_passed(piece.index, &piece.data));
+            if !scheduler.lock().unwrap().complete(piece.index) {
+                continue;
+            }
+            if piece_sender.send((piece.index, piece.data)).await.is_err() {
+                return Err("Download stopped".into());
+            }
+        }
+    }
+
+    fn report_banned(banned : Vec<IpAddr>) {
+        for peer_ip in banned {
+            eprintln!("Banned peer {peer_ip} for sending corrupt data");
+        }
+    }
+
+    // v2 torrents pad their files to a piece boundary, the padding is part of the pieces
+    fn piece_length(&self, piece_index : usize) -> Result<u64, std::io::Error> {
+        Ok(FileLayout::new(&self.metainfo.info)?.piece_size(piece_index))
+    }
+
+    // Whether a downloaded piece matches its hash from the metainfo
+    pub fn check_piece(&self, piece_index : usize, piece_data : &[u8]) -> bool {
+        FileLayout::new(&self.metainfo.info).is_ok_and(|layout| {
+            base16ct::lower::encode_string(&layout.piece_hash(piece_index, piece_data)) == self.pieces_hash[piece_index]
+        })
+    }
+
+    // Extensions offered to every peer we talk to
+    fn extensions(&self) -> ExtensionRegistry {
+        let mut extensions = ExtensionRegistry::new();
+        extensions.register(Box::new(MetadataExtension::serve(self.metainfo.info.to_bytes())));
+        extensions
+    }
+
+    // The v1 info hash, or the truncated v2 one for v2 only torrents
+    fn info_hash(&self) -> [u8; 20] {
+        self.metainfo.info.info_hashes()[0]
+    }
+
+    // For the swarm of `info_hash`, telling the peers we talk v2 when the torrent has a v2 swarm
+    fn our_handshake(&self, info_hash : [u8; 20]) -> Handshake {
+        match self.metainfo.info.is_v2() {
+            true => Handshake::new_v2(info_hash, self.peer_id),
+            false => Handshake::new(info_hash, self.peer_id)
+        }
     }
 }
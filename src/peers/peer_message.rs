@@ -2,6 +2,10 @@ use tokio_util::bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 use std::io::Error;
 
+// Longest message a peer may send, room for the bitfield of a torrent of millions of pieces. Anything longer
+// is a broken or hostile peer.
+const MESSAGE_MAX_LENGTH : u32 = 1024 * 1024;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageID {
@@ -14,7 +18,12 @@ pub enum MessageID {
     Request = 6,
     Piece = 7,
     Cancel = 8,
-    Port = 9
+    Port = 9,
+    Extended = 20,
+    // https://www.bittorrent.org/beps/bep_0052.html#new-messages
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23
 }
 
 #[derive(Debug, Clone)]
@@ -23,22 +32,25 @@ pub struct PeerMessage {
     pub payload : Option<Vec<u8>>
 }
 
+#[derive(Default)]
 pub struct PeerMessageDecoder {}
+#[derive(Default)]
 pub struct PeerMessageEncoder {}
 
+// Decoder and encoder bundled together so a peer socket can be wrapped in a single Framed
+#[derive(Default)]
+pub struct PeerMessageCodec {
+    decoder : PeerMessageDecoder,
+    encoder : PeerMessageEncoder
+}
+
 
 impl MessageID {
     pub fn should_have_payload(&self) -> bool {
-        match self {
-            MessageID::Choke => false,
-            MessageID::UnChoke => false,
-            MessageID::Interested => false,
-            MessageID::NotInterested => false,
-            _ => true
-        }
+        !matches!(self, MessageID::Choke | MessageID::UnChoke | MessageID::Interested | MessageID::NotInterested)
     }
 
-    pub fn to_u8(&self) -> u8 {
+    pub fn to_u8(self) -> u8 {
         match self {
             MessageID::Choke => 0,
             MessageID::UnChoke => 1,
@@ -49,7 +61,11 @@ impl MessageID {
             MessageID::Request => 6,
             MessageID::Piece => 7,
             MessageID::Cancel => 8,
-            MessageID::Port => 9
+            MessageID::Port => 9,
+            MessageID::Extended => 20,
+            MessageID::HashRequest => 21,
+            MessageID::Hashes => 22,
+            MessageID::HashReject => 23
         }
     }
 
@@ -65,6 +81,10 @@ impl MessageID {
             7 => Ok(MessageID::Piece),
             8 => Ok(MessageID::Cancel),
             9 => Ok(MessageID::Port),
+            20 => Ok(MessageID::Extended),
+            21 => Ok(MessageID::HashRequest),
+            22 => Ok(MessageID::Hashes),
+            23 => Ok(MessageID::HashReject),
             _ => Err(Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Invalid message id: {message_id}"),
@@ -76,7 +96,7 @@ impl MessageID {
 impl PeerMessage {
     pub fn new(message_id : MessageID, payload : Option<Vec<u8>>) -> Result<Self, Error> {
         if message_id.should_have_payload() {
-            if let None = payload {
+            if payload.is_none() {
                 return Err(Error::new(
                     std::io::ErrorKind::InvalidData,
                     format!("Peer message with message id {} should have a payload", message_id.to_u8()),
@@ -90,14 +110,12 @@ impl PeerMessage {
                     ));
                 }
             }
-        } else {
-            if let Some(ref payload) = payload {
-                if payload.is_empty() == false {
-                    return Err(Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Peer message with message id {} should not have a payload", message_id.to_u8()),
-                    ));
-                }
+        } else if let Some(ref payload) = payload {
+            if !payload.is_empty() {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Peer message with message id {} should not have a payload", message_id.to_u8()),
+                ));
             }
         }
 
@@ -125,6 +143,15 @@ impl PeerMessageEncoder {
     }
 }
 
+impl PeerMessageCodec {
+    pub fn new() -> Self {
+        PeerMessageCodec {
+            decoder: PeerMessageDecoder::new(),
+            encoder: PeerMessageEncoder::new()
+        }
+    }
+}
+
 impl Decoder for PeerMessageDecoder {
     type Item = PeerMessage;
     type Error = std::io::Error;
@@ -140,11 +167,16 @@ impl Decoder for PeerMessageDecoder {
 
         // This is a keep-alive message, discard it for now.
         if length_prefix == 0 {
-            println!("Received keep-alive message");
             src.advance(4);
             return self.decode(src);
         }
-        if src.len() < (4 + length_prefix) as usize {
+        if length_prefix > MESSAGE_MAX_LENGTH {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Peer message of {length_prefix} bytes is longer than the {MESSAGE_MAX_LENGTH} bytes allowed"),
+            ));
+        }
+        if src.len() < 4 + length_prefix as usize {
             return Ok(None);
         }
         src.advance(4);
@@ -153,7 +185,14 @@ impl Decoder for PeerMessageDecoder {
         src.advance(1);
 
         // Some type of messages do not have a payload
-        if message_id.should_have_payload() == false {
+        if !message_id.should_have_payload() {
+            // Anything after the id would be taken for the next message
+            if length_prefix != 1 {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Peer message with message id {} should not have a payload", message_id.to_u8()),
+                ));
+            }
             return Ok(Some(
                 PeerMessage {
                     message_id,
@@ -181,7 +220,7 @@ impl Encoder<PeerMessage> for PeerMessageEncoder {
         if dst.capacity() < item.size() + 4 {
             dst.reserve(item.size() + 4);
         }
-        dst.put_u32_ne(item.size() as u32);
+        dst.put_u32(item.size() as u32);
         dst.put_u8(item.message_id.to_u8());
         if let Some(payload) = item.payload {
             dst.extend_from_slice(&payload);
@@ -189,3 +228,20 @@ impl Encoder<PeerMessage> for PeerMessageEncoder {
         Ok(())
     }
 }
+
+impl Decoder for PeerMessageCodec {
+    type Item = PeerMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decoder.decode(src)
+    }
+}
+
+impl Encoder<PeerMessage> for PeerMessageCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encoder.encode(item, dst)
+    }
+}
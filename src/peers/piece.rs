@@ -1,5 +1,5 @@
 use tokio_util::bytes::{Buf, BytesMut};
-use tokio_util::codec::Decoder;
+use tokio_util::codec::{Decoder, Encoder};
 use crate::peers::BLOCK_MAX;
 
 pub struct Piece {
@@ -8,7 +8,10 @@ pub struct Piece {
     pub block : Vec<u8>
 }
 
+#[derive(Default)]
 pub struct PieceDecoder {}
+#[derive(Default)]
+pub struct PieceEncoder {}
 
 impl Piece {
     pub fn new(index : u32, begin : u32, block : Vec<u8>) -> Self {
@@ -26,6 +29,12 @@ impl PieceDecoder {
     }
 }
 
+impl PieceEncoder {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
 impl Decoder for PieceDecoder {
     type Item = Piece;
     type Error = std::io::Error;
@@ -44,7 +53,12 @@ impl Decoder for PieceDecoder {
         let begin = u32::from_be_bytes(begin_bytes);
         src.advance(4);
 
-        assert!(src.len() <= BLOCK_MAX as usize);
+        if src.len() > BLOCK_MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Block of {} bytes is larger than the {} bytes a peer may send", src.len(), BLOCK_MAX),
+            ));
+        }
 
         let block = src.to_vec();
         src.advance(block.len());
@@ -53,4 +67,17 @@ impl Decoder for PieceDecoder {
             Piece::new(index, begin, block)
         ))
     }
-}
\ No newline at end of file
+}
+
+impl Encoder<Piece> for PieceEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Piece, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(4 * 2 + item.block.len());
+        dst.extend_from_slice(&item.index.to_be_bytes());
+        dst.extend_from_slice(&item.begin.to_be_bytes());
+        dst.extend_from_slice(&item.block);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Error;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
+
+use crate::metainfo::FileLayout;
+use crate::peers::{Bitfield, PartialBlock, BLOCK_MAX};
+use crate::storage::Storage;
+
+// Sessions that fall further behind than this on new pieces miss some Have messages
+const NEW_PIECES_CAPACITY : usize = 1024;
+// Or this far behind on blocks arrived in endgame, some of their requests are then left to run their course
+const BLOCK_ARRIVALS_CAPACITY : usize = 1024;
+// Verified pieces are held in memory up to this many bytes, then written out in one go
+const WRITE_CACHE_SIZE : u64 = 16 * 1024 * 1024;
+
+// Offset of every block written for a piece and the peer that sent it
+type WrittenBlocks = Vec<(u64, Option<IpAddr>)>;
+
+// Pieces verified but not written to disk yet, by index so a flush writes them in file order
+#[derive(Default)]
+struct WriteCache {
+    pieces : BTreeMap<usize, Vec<u8>>,
+    size : u64
+}
+
+// The pieces we verified and the storage they were written to, blocks served to other peers are read back from there.
+// Only the pieces in the write cache are kept in memory, whatever the size of the torrent.
+pub struct PieceStore {
+    storage : Arc<dyn Storage>,
+    have : RwLock<Bitfield>,
+    cache : Mutex<WriteCache>,
+    // Blocks written for pieces not verified yet, with the peer that sent them
+    partial : Mutex<HashMap<usize, WrittenBlocks>>,
+    new_pieces : broadcast::Sender<usize>,
+    // Piece index and offset of the blocks received of pieces several peers are downloading
+    block_arrivals : broadcast::Sender<(usize, u64)>
+}
+
+impl PieceStore {
+    pub fn new(storage : Arc<dyn Storage>) -> Self {
+        let (new_pieces, _) = broadcast::channel(NEW_PIECES_CAPACITY);
+        let (block_arrivals, _) = broadcast::channel(BLOCK_ARRIVALS_CAPACITY);
+        PieceStore {
+            have: RwLock::new(Bitfield::new(storage.layout().piece_count())),
+            cache: Mutex::new(WriteCache::default()),
+            partial: Mutex::new(HashMap::new()),
+            storage,
+            new_pieces,
+            block_arrivals
+        }
+    }
+
+    pub fn layout(&self) -> &FileLayout {
+        self.storage.layout()
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub fn bitfield(&self) -> Bitfield {
+        self.have.read().unwrap().clone()
+    }
+
+    pub fn has_piece(&self, piece_index : usize) -> bool {
+        self.have.read().unwrap().has(piece_index)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.read().unwrap().is_complete()
+    }
+
+    // Pick up where an earlier run stopped: the pieces verified then and the blocks, by offset, of the others
+    pub fn restore(&self, have : Bitfield, partial : HashMap<usize, Vec<u64>>) {
+        *self.have.write().unwrap() = have;
+        *self.partial.lock().unwrap() = partial.into_iter()
+            .map(|(piece_index, begins)| (piece_index, begins.into_iter().map(|begin| (begin, None)).collect()))
+            .collect();
+    }
+
+    // Indexes of the pieces written from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<usize> {
+        self.new_pieces.subscribe()
+    }
+
+    // Blocks other peers sent us of the pieces downloaded in endgame
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<(usize, u64)> {
+        self.block_arrivals.subscribe()
+    }
+
+    // Tell the other peers downloading the piece to cancel their request for the block
+    pub fn block_arrived(&self, piece_index : usize, begin : u64) {
+        let _ = self.block_arrivals.send((piece_index, begin));
+    }
+
+    // The piece must have been checked against its hash already. It reaches the disk once the write cache fills up
+    // or on `flush`, it can be served to other peers right away either way. A piece that fails to be written
+    // is not kept, the pieces cached before it are tried again on the next write.
+    pub fn write_piece(&self, piece_index : usize, data : &[u8]) -> Result<(), Error> {
+        if data.len() as u64 != self.layout().piece_size(piece_index) {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Piece {piece_index} should be {} bytes long", self.layout().piece_size(piece_index)),
+            ));
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.pieces.insert(piece_index, data.to_vec()).is_none() {
+            cache.size += data.len() as u64;
+        }
+        if cache.size >= WRITE_CACHE_SIZE {
+            if let Err(err) = self.write_cached(&mut cache) {
+                if let Some(data) = cache.pieces.remove(&piece_index) {
+                    cache.size -= data.len() as u64;
+                }
+                return Err(err);
+            }
+        }
+        drop(cache);
+        self.partial.lock().unwrap().remove(&piece_index);
+        self.have.write().unwrap().set(piece_index);
+        // Nobody listening is fine
+        let _ = self.new_pieces.send(piece_index);
+        Ok(())
+    }
+
+    // Keep blocks of a piece nobody is downloading anymore, for whoever picks the piece up next
+    pub fn write_partial(&self, piece_index : usize, blocks : &[PartialBlock]) -> Result<(), Error> {
+        let mut partial = self.partial.lock().unwrap();
+        let written = partial.entry(piece_index).or_default();
+        for block in blocks {
+            if written.iter().any(|(begin, _)| *begin == block.begin) {
+                continue;
+            }
+            self.storage.write_block(piece_index, block.begin, &block.data)?;
+            written.push((block.begin, block.peer_ip));
+        }
+        Ok(())
+    }
+
+    // The blocks kept for a piece, they are forgotten here once taken
+    pub fn take_partial(&self, piece_index : usize) -> Vec<PartialBlock> {
+        let Some(written) = self.partial.lock().unwrap().remove(&piece_index) else {
+            return vec![];
+        };
+        let piece_size = self.layout().piece_size(piece_index);
+        written.into_iter().filter_map(|(begin, peer_ip)| {
+            let length = BLOCK_MAX.min(piece_size.saturating_sub(begin));
+            let data = self.storage.read_block(piece_index, begin, length).ok()?;
+            Some(PartialBlock { begin, data, peer_ip })
+        }).collect()
+    }
+
+    // Offsets of the blocks kept, by piece
+    pub fn partial_blocks(&self) -> HashMap<usize, Vec<u64>> {
+        self.partial.lock().unwrap().iter()
+            .map(|(piece_index, written)| (*piece_index, written.iter().map(|(begin, _)| *begin).collect()))
+            .collect()
+    }
+
+    // Write every cached piece to the storage and make it durable
+    pub fn flush(&self) -> Result<(), Error> {
+        self.write_cached(&mut self.cache.lock().unwrap())?;
+        self.storage.flush()
+    }
+
+    pub fn read_block(&self, piece_index : usize, begin : u64, length : u64) -> Result<Vec<u8>, Error> {
+        if !self.has_piece(piece_index) || begin + length > self.layout().piece_size(piece_index) {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Block at {begin} of piece {piece_index} is not available"),
+            ));
+        }
+        if let Some(piece) = self.cache.lock().unwrap().pieces.get(&piece_index) {
+            return Ok(piece[begin as usize..(begin + length) as usize].to_vec());
+        }
+        self.storage.read_block(piece_index, begin, length)
+    }
+
+    // A piece that fails to be written stays cached
+    fn write_cached(&self, cache : &mut WriteCache) -> Result<(), Error> {
+        while let Some((piece_index, data)) = cache.pieces.pop_first() {
+            if let Err(err) = self.storage.write_block(piece_index, 0, &data) {
+                cache.pieces.insert(piece_index, data);
+                return Err(err);
+            }
+            cache.size -= data.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PieceStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("Failed to write downloaded pieces: {err}");
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::peers::BLOCK_MAX;
+use crate::peers::piece::Piece;
+use crate::peers::request::Request;
+
+// Requests kept in flight before we know how fast the peer is
+const INITIAL_QUEUE_DEPTH : usize = 4;
+const MIN_QUEUE_DEPTH : usize = 2;
+// Peers that don't tell us their reqq are assumed to keep this many requests
+const DEFAULT_PEER_QUEUE_DEPTH : usize = 250;
+// Enough requests are kept in flight to cover this much of the peer's measured throughput
+const QUEUE_TIME : Duration = Duration::from_secs(3);
+// Throughput is measured over windows of this length
+const RATE_WINDOW : Duration = Duration::from_secs(1);
+
+// A block of a piece that isn't complete yet, and the peer that sent it when we know
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub begin : u64,
+    pub data : Vec<u8>,
+    pub peer_ip : Option<IpAddr>
+}
+
+// A piece with all its blocks, not checked against its hash yet
+pub struct DownloadedPiece {
+    pub index : usize,
+    pub data : Vec<u8>,
+    // The peer that sent each block
+    pub contributors : Vec<Option<IpAddr>>
+}
+
+// The blocks received so far of a piece, shared by every peer downloading it: in endgame a peer
+// only asks for the blocks none of the others has
+pub struct PieceBlocks {
+    index : usize,
+    length : u64,
+    data : Vec<u8>,
+    received : Vec<bool>,
+    contributors : Vec<Option<IpAddr>>,
+    received_data : u64,
+    // Counted by the scheduler, more than one in endgame
+    pub downloaders : usize
+}
+
+pub type SharedPiece = Arc<Mutex<PieceBlocks>>;
+
+impl PieceBlocks {
+    pub fn new(index : usize, length : u64) -> Self {
+        let blocks = length.div_ceil(BLOCK_MAX) as usize;
+        PieceBlocks {
+            index,
+            length,
+            data: vec![0; length as usize],
+            received: vec![false; blocks],
+            contributors: vec![None; blocks],
+            received_data: 0,
+            downloaders: 0
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received_data == self.length
+    }
+
+    // The piece failed its hash check, every block is asked for again
+    pub fn reset(&mut self) {
+        let downloaders = self.downloaders;
+        *self = PieceBlocks { downloaders, ..Self::new(self.index, self.length) };
+    }
+
+    fn store(&mut self, begin : u64, data : &[u8], peer_ip : Option<IpAddr>) {
+        let block = (begin / BLOCK_MAX) as usize;
+        self.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        self.received[block] = true;
+        self.contributors[block] = peer_ip;
+        self.received_data += data.len() as u64;
+    }
+
+    fn block_request(&self, block : usize) -> Request {
+        let begin = block as u64 * BLOCK_MAX;
+        Request::new(self.index as u32, begin as u32, BLOCK_MAX.min(self.length - begin) as u32)
+    }
+}
+
+// One piece being downloaded from this peer, block by block
+struct PieceDownload {
+    index : usize,
+    blocks : SharedPiece,
+    // Blocks asked of this peer and not answered yet
+    requested : Vec<bool>
+}
+
+impl PieceDownload {
+    // Blocks nobody has sent yet that this peer wasn't asked for
+    fn unrequested_blocks(&self) -> usize {
+        let blocks = self.blocks.lock().unwrap();
+        (0..self.requested.len()).filter(|&block| !self.requested[block] && !blocks.received[block]).count()
+    }
+}
+
+// Block requests sent to one peer. Up to `depth` blocks are kept in flight across piece boundaries
+// so the link never idles waiting on a round trip, the depth following the throughput we measure
+// and capped by the queue size the peer told us about.
+pub struct RequestPipeline {
+    peer_ip : Option<IpAddr>,
+    pieces : VecDeque<PieceDownload>,
+    in_flight : Vec<Request>,
+    depth : usize,
+    max_depth : usize,
+    window_start : Instant,
+    window_bytes : u64
+}
+
+impl RequestPipeline {
+    // Blocks received are credited to `peer_ip`
+    pub fn new(peer_ip : Option<IpAddr>) -> Self {
+        RequestPipeline {
+            peer_ip,
+            pieces: VecDeque::new(),
+            in_flight: vec![],
+            depth: INITIAL_QUEUE_DEPTH,
+            max_depth: DEFAULT_PEER_QUEUE_DEPTH,
+            window_start: Instant::now(),
+            window_bytes: 0
+        }
+    }
+
+    // The reqq of the peer's extended handshake
+    pub fn set_peer_queue_depth(&mut self, reqq : Option<u32>) {
+        self.max_depth = reqq.map_or(DEFAULT_PEER_QUEUE_DEPTH, |reqq| reqq as usize).max(1);
+        self.depth = self.depth.min(self.max_depth);
+    }
+
+    // `blocks` holds what the other peers downloading the piece received, `received` are blocks of the piece
+    // we already have from an earlier download. Only the others are requested.
+    pub fn add_piece(&mut self, blocks : SharedPiece, received : Vec<PartialBlock>) {
+        let mut piece = blocks.lock().unwrap();
+        for block in received {
+            let fits = block.begin.is_multiple_of(BLOCK_MAX) && block.data.len() as u64 == BLOCK_MAX.min(piece.length.saturating_sub(block.begin));
+            if fits && !piece.received[(block.begin / BLOCK_MAX) as usize] {
+                piece.store(block.begin, &block.data, block.peer_ip);
+            }
+        }
+        if piece.is_complete() && piece.downloaders <= 1 {
+            // Complete pieces are only handed out by `received`, ask for everything again
+            piece.reset();
+        }
+        let (index, block_count) = (piece.index, piece.received.len());
+        drop(piece);
+        self.pieces.push_back(PieceDownload { index, blocks, requested: vec![false; block_count] });
+    }
+
+    // Whether other peers are downloading the piece too
+    pub fn is_shared(&self, piece_index : usize) -> bool {
+        self.pieces.iter().find(|piece| piece.index == piece_index)
+            .is_some_and(|piece| piece.blocks.lock().unwrap().downloaders > 1)
+    }
+
+    // Nothing asked of the peer, so it has no reason to send us anything
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    // True while the blocks not requested yet can't refill a whole window
+    pub fn wants_piece(&self) -> bool {
+        self.pieces.iter().map(PieceDownload::unrequested_blocks).sum::<usize>() < self.depth
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    // Pieces handed to this peer and not completed yet
+    pub fn pieces(&self) -> Vec<usize> {
+        self.pieces.iter().map(|piece| piece.index).collect()
+    }
+
+    // The blocks received so far of the pieces not complete yet
+    pub fn partial_pieces(&self) -> Vec<(usize, Vec<PartialBlock>)> {
+        self.pieces.iter().filter_map(|piece| {
+            let piece = piece.blocks.lock().unwrap();
+            if piece.received_data == 0 || piece.is_complete() {
+                return None;
+            }
+            let blocks = (0..piece.received.len()).filter(|&block| piece.received[block]).map(|block| {
+                let request = piece.block_request(block);
+                let begin = request.begin() as usize;
+                PartialBlock {
+                    begin: begin as u64,
+                    data: piece.data[begin..begin + request.length() as usize].to_vec(),
+                    peer_ip: piece.contributors[block]
+                }
+            }).collect();
+            Some((piece.index, blocks))
+        }).collect()
+    }
+
+    // Requests to send to fill the window, oldest pieces first
+    pub fn next_requests(&mut self) -> Vec<Request> {
+        if self.in_flight.is_empty() {
+            // Time spent with nothing asked doesn't say anything about the peer's throughput
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        let mut requests : Vec<Request> = vec![];
+        for piece in self.pieces.iter_mut() {
+            let blocks = piece.blocks.lock().unwrap();
+            for block in 0..piece.requested.len() {
+                if self.in_flight.len() >= self.depth {
+                    return requests;
+                }
+                if !piece.requested[block] && !blocks.received[block] {
+                    piece.requested[block] = true;
+                    let request = blocks.block_request(block);
+                    self.in_flight.push(request);
+                    requests.push(request);
+                }
+            }
+        }
+        requests
+    }
+
+    // Another peer sent a block of a piece we download too, returns our request for it to cancel
+    pub fn arrived(&mut self, piece_index : usize, begin : u64) -> Option<Request> {
+        let piece = self.pieces.iter_mut().find(|piece| piece.index == piece_index)?;
+        let block = (begin / BLOCK_MAX) as usize;
+        if !piece.requested.get(block).copied().unwrap_or(false) {
+            return None;
+        }
+        piece.requested[block] = false;
+        let position = self.in_flight.iter().position(|request| request.index() as usize == piece_index && request.begin() as u64 == begin)?;
+        Some(self.in_flight.swap_remove(position))
+    }
+
+    // Forget a piece another peer completed, returns the requests to cancel
+    pub fn cancel(&mut self, piece_index : usize) -> Vec<Request> {
+        self.pieces.retain(|piece| piece.index != piece_index);
+        let (cancelled, in_flight) = self.in_flight.iter().partition(|request| request.index() as usize == piece_index);
+        self.in_flight = in_flight;
+        cancelled
+    }
+
+    // A choking peer drops our requests, they go out again once unchoked
+    pub fn choked(&mut self) {
+        for request in self.in_flight.drain(..) {
+            if let Some(piece) = self.pieces.iter_mut().find(|piece| piece.index == request.index() as usize) {
+                piece.requested[(request.begin() as u64 / BLOCK_MAX) as usize] = false;
+            }
+        }
+    }
+
+    // Store a received block, returns the piece it completes. Blocks we did not ask for, or that another peer
+    // sent first, are ignored.
+    pub fn received(&mut self, block : Piece) -> Option<DownloadedPiece> {
+        let position = self.in_flight.iter().position(|request| {
+            request.index() == block.index && request.begin() == block.begin && request.length() as usize == block.block.len()
+        })?;
+        self.in_flight.swap_remove(position);
+        self.measure(block.block.len() as u64);
+
+        let position = self.pieces.iter().position(|piece| piece.index == block.index as usize)?;
+        let piece = &mut self.pieces[position];
+        let block_index = (block.begin as u64 / BLOCK_MAX) as usize;
+        piece.requested[block_index] = false;
+        let mut blocks = piece.blocks.lock().unwrap();
+        if blocks.received[block_index] {
+            return None;
+        }
+        blocks.store(block.begin as u64, &block.block, self.peer_ip);
+        if !blocks.is_complete() {
+            return None;
+        }
+        // The other peers downloading the piece drop it once it is verified, or ask for it again if it isn't
+        let downloaded = DownloadedPiece {
+            index: blocks.index,
+            data: std::mem::take(&mut blocks.data),
+            contributors: blocks.contributors.clone()
+        };
+        drop(blocks);
+        self.pieces.remove(position);
+        Some(downloaded)
+    }
+
+    // Keep enough requests in flight to cover QUEUE_TIME at the rate the peer is sending
+    fn measure(&mut self, bytes : u64) {
+        self.window_bytes += bytes;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let depth = (rate * QUEUE_TIME.as_secs_f64() / BLOCK_MAX as f64) as usize;
+        self.depth = depth.max(MIN_QUEUE_DEPTH).min(self.max_depth);
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+    }
+}
@@ -1,12 +1,15 @@
 use tokio_util::bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Request {
     index : [u8; 4],
     begin : [u8; 4],
     length : [u8; 4],
 }
+#[derive(Default)]
 pub struct RequestDecoder {}
+#[derive(Default)]
 pub struct RequestEncoder {}
 
 impl Request {
@@ -21,6 +24,18 @@ impl Request {
     pub fn size() -> usize {
         4 * 3
     }
+
+    pub fn index(&self) -> u32 {
+        u32::from_be_bytes(self.index)
+    }
+
+    pub fn begin(&self) -> u32 {
+        u32::from_be_bytes(self.begin)
+    }
+
+    pub fn length(&self) -> u32 {
+        u32::from_be_bytes(self.length)
+    }
 }
 
 impl RequestDecoder {
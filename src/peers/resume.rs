@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::metainfo::FileLayout;
+use crate::peers::{Bitfield, TrackerResponse, TransferStats, BLOCK_MAX, COMPACT_PEER_V4_LENGTH, COMPACT_PEER_V6_LENGTH};
+
+// Size and modification time of a file of the torrent when the resume data was saved
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeFile {
+    pub length : u64,
+    pub mtime : u64
+}
+
+// Blocks on disk of a piece that wasn't complete yet, one bit per block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumePiece {
+    pub piece : usize,
+    pub blocks : ByteBuf
+}
+
+// Progress of a torrent saved between runs, bencoded next to the data. Trusted as is when the files
+// weren't touched since, otherwise the pieces it lists are checked again before being used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info-hash")]
+    pub info_hash : ByteBuf,
+    // Bitfield of the verified pieces
+    pub pieces : ByteBuf,
+    pub files : Vec<ResumeFile>,
+    #[serde(default)]
+    pub partial : Vec<ResumePiece>,
+    pub uploaded : u64,
+    pub downloaded : u64,
+    // Compact peer lists, as trackers send them
+    #[serde(default)]
+    pub peers : ByteBuf,
+    #[serde(default)]
+    pub peers6 : ByteBuf
+}
+
+impl ResumeData {
+    // `partial` holds the offsets of the blocks kept for each piece that isn't complete
+    pub fn new(info_hash : [u8; 20], have : &Bitfield, partial : &HashMap<usize, Vec<u64>>, layout : &FileLayout,
+               download_directory : &Path, stats : &TransferStats, known_peers : &[SocketAddr]) -> Result<Self, Error> {
+        let mut peers : Vec<u8> = vec![];
+        let mut peers6 : Vec<u8> = vec![];
+        for peer in known_peers {
+            let (list, ip) = match peer {
+                SocketAddr::V4(address) => (&mut peers, address.ip().octets().to_vec()),
+                SocketAddr::V6(address) => (&mut peers6, address.ip().octets().to_vec())
+            };
+            list.extend_from_slice(&ip);
+            list.extend_from_slice(&peer.port().to_be_bytes());
+        }
+        let partial = partial.iter().map(|(piece_index, begins)| {
+            let mut blocks = Bitfield::new(layout.piece_size(*piece_index).div_ceil(BLOCK_MAX) as usize);
+            for begin in begins {
+                blocks.set((begin / BLOCK_MAX) as usize);
+            }
+            ResumePiece { piece: *piece_index, blocks: ByteBuf::from(blocks.as_bytes().to_vec()) }
+        }).collect();
+
+        Ok(ResumeData {
+            info_hash: ByteBuf::from(info_hash.to_vec()),
+            pieces: ByteBuf::from(have.as_bytes().to_vec()),
+            files: Self::file_stats(layout, download_directory)?,
+            partial,
+            uploaded: stats.uploaded(),
+            downloaded: stats.downloaded(),
+            peers: ByteBuf::from(peers),
+            peers6: ByteBuf::from(peers6)
+        })
+    }
+
+    // None when there is no resume file or it can't be read
+    pub fn load(path : &Path) -> Option<Self> {
+        serde_bencode::from_bytes(&fs::read(path).ok()?).ok()
+    }
+
+    // Written aside first so a crash never leaves half a resume file
+    pub fn save(&self, path : &Path) -> Result<(), Error> {
+        let bytes = serde_bencode::to_bytes(self).map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let temporary_path = path.with_extension("resume.part");
+        fs::write(&temporary_path, bytes)?;
+        fs::rename(temporary_path, path)
+    }
+
+    pub fn is_for(&self, info_hash : [u8; 20]) -> bool {
+        self.info_hash.as_slice() == info_hash
+    }
+
+    // True when the files are the ones we saved, nothing needs to be checked again
+    pub fn files_unchanged(&self, layout : &FileLayout, download_directory : &Path) -> bool {
+        Self::file_stats(layout, download_directory).is_ok_and(|files| files == self.files)
+    }
+
+    pub fn have(&self, piece_count : usize) -> Option<Bitfield> {
+        Bitfield::from_bytes(&self.pieces, piece_count)
+    }
+
+    // Offsets of the blocks kept, by piece
+    pub fn partial_blocks(&self, layout : &FileLayout) -> HashMap<usize, Vec<u64>> {
+        self.partial.iter().filter(|piece| piece.piece < layout.piece_count()).filter_map(|piece| {
+            let block_count = layout.piece_size(piece.piece).div_ceil(BLOCK_MAX) as usize;
+            let blocks = Bitfield::from_bytes(&piece.blocks, block_count)?;
+            Some((piece.piece, blocks.pieces().map(|block| block as u64 * BLOCK_MAX).collect()))
+        }).collect()
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.chunks_exact(COMPACT_PEER_V4_LENGTH)
+            .chain(self.peers6.chunks_exact(COMPACT_PEER_V6_LENGTH))
+            .filter_map(TrackerResponse::compact_peer)
+            .collect()
+    }
+
+    fn file_stats(layout : &FileLayout, download_directory : &Path) -> Result<Vec<ResumeFile>, Error> {
+        layout.data_files().map(|file_index| {
+            let metadata = fs::metadata(layout.full_path(download_directory, file_index))?;
+            let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map(|mtime| mtime.as_secs()).unwrap_or(0);
+            Ok(ResumeFile { length: metadata.len(), mtime })
+        }).collect()
+    }
+}
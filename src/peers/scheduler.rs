@@ -0,0 +1,173 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use rand::seq::IteratorRandom;
+use crate::peers::{Bitfield, PieceBlocks, SharedPiece};
+
+pub struct ScheduledPiece {
+    pub index : usize,
+    pub length : u64,
+    pub hash : String,
+    // Shared with the other peers downloading the piece
+    pub blocks : SharedPiece
+}
+
+// Hands out pieces to the peers of a swarm download, a piece is given to one peer at a time
+// and goes back to the queue if that peer fails to deliver it. In endgame, once every piece left
+// is being downloaded, idle peers ask for the blocks of those pieces nobody sent yet so a slow peer
+// can't hold up the end.
+// https://wiki.theory.org/BitTorrentSpecification#Piece_downloading_strategy
+pub struct PieceScheduler {
+    pieces_hash : Vec<String>,
+    piece_length : u64,
+    total_length : u64,
+    pending : BTreeSet<usize>,
+    // The blocks received of each piece being downloaded, along with how many peers are downloading it
+    in_progress : HashMap<usize, SharedPiece>,
+    // How many connected peers have each piece
+    availability : Vec<u32>,
+    completed : Bitfield
+}
+
+impl PieceScheduler {
+    pub fn new(pieces_hash : Vec<String>, piece_length : u64, total_length : u64) -> Self {
+        PieceScheduler {
+            pending: (0..pieces_hash.len()).collect(),
+            availability: vec![0; pieces_hash.len()],
+            completed: Bitfield::new(pieces_hash.len()),
+            pieces_hash,
+            piece_length,
+            total_length,
+            in_progress: HashMap::new()
+        }
+    }
+
+    // Pieces we already have from an earlier run are never handed out
+    pub fn restore(&mut self, have : &Bitfield) {
+        for piece_index in have.pieces() {
+            if self.pending.remove(&piece_index) {
+                self.completed.set(piece_index);
+            }
+        }
+    }
+
+    // Count the pieces a peer announced with its bitfield or Have messages, `counted` keeps track of those already counted
+    pub fn add_peer_pieces(&mut self, counted : &mut Bitfield, new_pieces : &[usize]) {
+        for &piece_index in new_pieces {
+            if piece_index < self.availability.len() && !counted.has(piece_index) {
+                counted.set(piece_index);
+                self.availability[piece_index] += 1;
+            }
+        }
+    }
+
+    // The peer is gone, its pieces no longer count
+    pub fn remove_peer_pieces(&mut self, counted : &Bitfield) {
+        for piece_index in counted.pieces() {
+            if let Some(count) = self.availability.get_mut(piece_index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    // The rarest pending piece the peer can give us, ties broken at random so peers don't all
+    // go for the same piece. The first piece is picked at random: a rare piece is slow to get,
+    // and having something to share early is what gets us unchoked.
+    // In endgame the peer gets the piece fewest others are downloading among those it doesn't have `queued` already
+    // and that still miss some blocks.
+    pub fn next_piece(&mut self, peer_pieces : &Bitfield, queued : &[usize]) -> Option<ScheduledPiece> {
+        let (index, blocks) = match self.pick_pending(peer_pieces) {
+            Some(index) => {
+                self.pending.remove(&index);
+                let blocks = Arc::new(Mutex::new(PieceBlocks::new(index, self.piece_length(index))));
+                self.in_progress.insert(index, blocks.clone());
+                (index, blocks)
+            },
+            None if self.pending.is_empty() => self.in_progress.iter()
+                .filter(|(index, _)| peer_pieces.has(**index) && !queued.contains(index))
+                .filter_map(|(index, blocks)| {
+                    let piece = blocks.lock().unwrap();
+                    (!piece.is_complete()).then_some((piece.downloaders, *index, blocks))
+                })
+                .min_by_key(|(downloaders, index, _)| (*downloaders, *index))
+                .map(|(_, index, blocks)| (index, blocks.clone()))?,
+            None => return None
+        };
+        blocks.lock().unwrap().downloaders += 1;
+        Some(ScheduledPiece {
+            index,
+            length: self.piece_length(index),
+            hash: self.pieces_hash[index].clone(),
+            blocks
+        })
+    }
+
+    fn pick_pending(&self, peer_pieces : &Bitfield) -> Option<usize> {
+        let candidates = self.pending.iter().copied().filter(|&index| peer_pieces.has(index));
+        if self.completed.is_empty() {
+            return candidates.choose(&mut rand::thread_rng());
+        }
+        let candidates : Vec<usize> = candidates.collect();
+        let rarest = candidates.iter().map(|&index| self.availability[index]).min()?;
+        candidates.into_iter()
+            .filter(|&index| self.availability[index] == rarest)
+            .choose(&mut rand::thread_rng())
+    }
+
+    // Returns false when another peer completed the piece first, the copy can then be dropped
+    pub fn complete(&mut self, piece_index : usize) -> bool {
+        if self.in_progress.remove(&piece_index).is_none() {
+            return false;
+        }
+        self.completed.set(piece_index);
+        true
+    }
+
+    // A completed piece we could not keep, it is downloaded again
+    pub fn retry(&mut self, piece_index : usize) {
+        if self.completed.has(piece_index) {
+            self.completed.unset(piece_index);
+            self.pending.insert(piece_index);
+        }
+    }
+
+    pub fn is_complete(&self, piece_index : usize) -> bool {
+        self.completed.has(piece_index)
+    }
+
+    // A peer gave up on a piece, it goes back to the queue once nobody else is downloading it
+    pub fn release(&mut self, piece_index : usize) {
+        let Some(blocks) = self.in_progress.get(&piece_index) else { return };
+        let downloaders = {
+            let mut blocks = blocks.lock().unwrap();
+            blocks.downloaders = blocks.downloaders.saturating_sub(1);
+            blocks.downloaders
+        };
+        if downloaders == 0 {
+            self.in_progress.remove(&piece_index);
+            self.pending.insert(piece_index);
+        }
+    }
+
+    // The piece failed its hash check, the peers still downloading it start over and the one that sent it lets go
+    pub fn failed(&mut self, piece_index : usize) {
+        if let Some(blocks) = self.in_progress.get(&piece_index) {
+            blocks.lock().unwrap().reset();
+        }
+        self.release(piece_index);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.completed.is_complete()
+    }
+
+    pub fn piece_length(&self, piece_index : usize) -> u64 {
+        if piece_index == self.pieces_hash.len() - 1 {
+            match self.total_length % self.piece_length {
+                0 => self.piece_length,
+                len => len
+            }
+        } else {
+            self.piece_length
+        }
+    }
+}
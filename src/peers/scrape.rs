@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::peers::{TrackerError, UdpTracker, HTTP_CLIENT, QUICK_RETRANSMISSIONS};
+
+// https://www.bittorrent.org/beps/bep_0048.html
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct ScrapeFile {
+    // Peers with the whole torrent
+    pub complete : u64,
+    // Peers still downloading
+    pub incomplete : u64,
+    // How many times the tracker saw the torrent complete
+    pub downloaded : u64,
+    pub name : Option<String>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason : Option<String>,
+    #[serde(default)]
+    files : BTreeMap<ByteBuf, ScrapeFile>
+}
+
+impl Display for ScrapeFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Complete: {}", self.complete)?;
+        writeln!(f, "Incomplete: {}", self.incomplete)?;
+        write!(f, "Downloaded: {}", self.downloaded)
+    }
+}
+
+// By convention the scrape url is the announce url with its last path component going from "announce" to "scrape",
+// trackers whose announce url does not follow it can't be scraped.
+pub fn scrape_url(announce_url : &str) -> Option<String> {
+    let (path, query) = match announce_url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce_url, None)
+    };
+    let (base, last_component) = path.rsplit_once('/')?;
+    let suffix = last_component.strip_prefix("announce")?;
+    let mut url = format!("{base}/scrape{suffix}");
+    if let Some(query) = query {
+        url = url + "?" + query;
+    }
+    Some(url)
+}
+
+// Ask a tracker about several torrents at once, torrents it does not know about are left out of the answer
+pub async fn scrape(tracker_url : &str, info_hashes : &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeFile>, Box<dyn std::error::Error + Send + Sync>> {
+    if tracker_url.starts_with("udp://") {
+        let files = UdpTracker::connect(tracker_url).await?.with_max_retransmissions(QUICK_RETRANSMISSIONS).scrape(info_hashes).await?;
+        return Ok(info_hashes.iter().copied().zip(files).collect());
+    }
+
+    let mut url = scrape_url(tracker_url).ok_or_else(|| format!("Tracker {tracker_url} does not support scrape"))?;
+    let mut separator = if url.contains('?') { "&" } else { "?" };
+    for info_hash in info_hashes {
+        url = url + separator + "info_hash=" + &urlencoding::encode_binary(info_hash);
+        separator = "&";
+    }
+    let body = HTTP_CLIENT.get(url)
+        .send()
+        .await?
+        .bytes()
+        .await?;
+    let scrape_response : ScrapeResponse = serde_bencode::from_bytes(&body)
+        .map_err(|err| TrackerError::InvalidResponse(err.to_string()))?;
+    if let Some(reason) = scrape_response.failure_reason {
+        return Err(TrackerError::Failure(reason).into());
+    }
+    Ok(scrape_response.files.into_iter()
+        .filter_map(|(info_hash, file)| Some((<[u8; 20]>::try_from(info_hash.as_slice()).ok()?, file)))
+        .collect())
+}
@@ -0,0 +1,331 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::peers::{Bitfield, DownloadedPiece, PartialBlock, PeerConnection, PieceBlocks, Request, RequestPipeline, SharedPiece, Uploader};
+use crate::peers::extension::{ExtendedHandshake, ExtensionRegistry};
+use crate::peers::peer_message::{MessageID, PeerMessage};
+use crate::peers::piece::PieceDecoder;
+use crate::peers::request::RequestEncoder;
+
+// A peer that stays silent for this long is considered dead
+pub const PEER_TIMEOUT : Duration = Duration::from_secs(30);
+// With nothing asked of it, a peer has no reason to talk: look for something else to ask this often
+const IDLE_POLL_INTERVAL : Duration = Duration::from_millis(500);
+
+// Protocol state kept for one peer across several piece downloads
+pub struct PeerSession {
+    pub connection : PeerConnection,
+    extensions : ExtensionRegistry,
+    uploader : Option<Uploader>,
+    peer_pieces : Option<Bitfield>,
+    // Announced by a bitfield or Have since the last `take_new_pieces`
+    new_pieces : Vec<usize>,
+    pipeline : RequestPipeline,
+    // Blocks other peers sent of the pieces downloaded in endgame
+    block_arrivals : Option<broadcast::Receiver<(usize, u64)>>,
+    peer_choking : bool,
+    am_interested : bool
+}
+
+impl PeerSession {
+    pub fn new(connection : PeerConnection, extensions : ExtensionRegistry) -> Self {
+        let peer_ip = connection.peer_address.ip();
+        PeerSession {
+            connection,
+            extensions,
+            uploader: None,
+            peer_pieces: None,
+            new_pieces: vec![],
+            pipeline: RequestPipeline::new(Some(peer_ip)),
+            block_arrivals: None,
+            peer_choking: true,
+            am_interested: false
+        }
+    }
+
+    // Serve the pieces of `uploader` to this peer while the session lasts. The torrent being known,
+    // the pieces the peer has are tracked from then on.
+    pub fn with_uploader(mut self, uploader : Uploader) -> Self {
+        self.peer_pieces = Some(Bitfield::new(uploader.store().layout().piece_count()));
+        self.block_arrivals = Some(uploader.store().subscribe_blocks());
+        self.uploader = Some(uploader);
+        self
+    }
+
+    pub fn peer_pieces(&self) -> Option<&Bitfield> {
+        self.peer_pieces.as_ref()
+    }
+
+    // Pieces the peer told us about since the last call
+    pub fn take_new_pieces(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.new_pieces)
+    }
+
+    // Send whatever has to go out right after the handshake
+    pub async fn start(&mut self, listen_port : u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(bitfield) = self.uploader.as_ref().map(Uploader::bitfield_message).transpose()?.flatten() {
+            self.connection.send(bitfield).await?;
+        }
+        if self.connection.handshake.supports_extensions() {
+            let handshake = self.extensions.handshake(self.connection.peer_address, listen_port);
+            self.connection.send(handshake.into_peer_message()?).await?;
+        }
+        Ok(())
+    }
+
+    pub fn peer_extensions(&self) -> Option<&ExtendedHandshake> {
+        self.extensions.peer_handshake()
+    }
+
+    // Wait for the next message. Extended messages and requests for our pieces are answered on the way,
+    // blocks the peer asked for go out whenever it has nothing else to say.
+    pub async fn receive(&mut self) -> Result<PeerMessage, Box<dyn std::error::Error + Send + Sync>> {
+        match self.next_message(Some(PEER_TIMEOUT)).await? {
+            Some(peer_message) => Ok(peer_message),
+            None => Err(format!("Peer {} timed out", self.connection.peer_address).into())
+        }
+    }
+
+    // Keep uploading to the peer until it goes away, a quiet peer is fine here
+    pub async fn serve(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            self.next_message(None).await?;
+        }
+    }
+
+    // Handle whatever the peer sends for a while, returns early with the first message
+    pub async fn wait(&mut self, duration : Duration) -> Result<Option<PeerMessage>, Box<dyn std::error::Error + Send + Sync>> {
+        self.next_message(Some(duration)).await
+    }
+
+    // With an `idle_timeout`, also returns None once other peers sent every block we were waiting for
+    async fn next_message(&mut self, idle_timeout : Option<Duration>) -> Result<Option<PeerMessage>, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            if self.cancel_arrived_blocks().await? && idle_timeout.is_some() && self.pipeline.is_idle() {
+                return Ok(None);
+            }
+            if let Some(uploader) = &mut self.uploader {
+                for message in uploader.pending_messages()? {
+                    self.connection.send(message).await?;
+                }
+                if uploader.has_requests() {
+                    // Serve one block at a time so a Cancel can still catch the requests behind it
+                    match self.connection.try_receive()? {
+                        Some(peer_message) => return self.handle(peer_message).await.map(Some),
+                        None => {
+                            if let Some(block) = uploader.next_block()? {
+                                self.connection.send(block).await?;
+                            }
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            // Wake up now and then to announce the pieces completed in the meantime
+            let deadline = Instant::now() + idle_timeout.unwrap_or(PEER_TIMEOUT);
+            let choke_changed = async {
+                match self.uploader.as_ref() {
+                    Some(uploader) => uploader.peer().choke_changed.notified().await,
+                    None => std::future::pending().await
+                }
+            };
+            let block_arrived = async {
+                match self.block_arrivals.as_mut() {
+                    Some(block_arrivals) => block_arrivals.recv().await,
+                    None => std::future::pending().await
+                }
+            };
+            let peer_message = tokio::select! {
+                peer_message = self.connection.receive() => peer_message,
+                _ = choke_changed => continue,
+                block = block_arrived => {
+                    match block {
+                        Ok((piece_index, begin)) => if self.cancel_block(piece_index, begin).await? && idle_timeout.is_some() && self.pipeline.is_idle() {
+                            return Ok(None);
+                        },
+                        Err(RecvError::Lagged(_)) => {},
+                        Err(RecvError::Closed) => self.block_arrivals = None
+                    }
+                    continue;
+                },
+                _ = sleep_until(deadline) => match idle_timeout {
+                    Some(_) => return Ok(None),
+                    None => continue
+                }
+            };
+            let Some(peer_message) = peer_message else {
+                return Err(format!("Peer {} closed the connection", self.connection.peer_address).into());
+            };
+            return self.handle(peer_message).await.map(Some);
+        }
+    }
+
+    // Cancel our requests for the blocks other peers sent in the meantime, true when there were some
+    async fn cancel_arrived_blocks(&mut self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut cancelled = false;
+        while let Some(block_arrivals) = &mut self.block_arrivals {
+            match block_arrivals.try_recv() {
+                Ok((piece_index, begin)) => cancelled |= self.cancel_block(piece_index, begin).await?,
+                Err(TryRecvError::Lagged(_)) => {},
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => self.block_arrivals = None
+            }
+        }
+        Ok(cancelled)
+    }
+
+    // Another peer sent a block we asked for too, returns whether we did
+    async fn cancel_block(&mut self, piece_index : usize, begin : u64) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(request) = self.pipeline.arrived(piece_index, begin) else { return Ok(false) };
+        self.send_cancel(request).await?;
+        Ok(true)
+    }
+
+    async fn send_cancel(&mut self, request : Request) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut request_bytes : BytesMut = BytesMut::new();
+        RequestEncoder::new().encode(request, &mut request_bytes)?;
+        self.connection.send(PeerMessage::new(MessageID::Cancel, Some(request_bytes.to_vec()))?).await?;
+        Ok(())
+    }
+
+    // Whatever way the message came in, the peer's state follows it
+    async fn handle(&mut self, peer_message : PeerMessage) -> Result<PeerMessage, Box<dyn std::error::Error + Send + Sync>> {
+        match peer_message.message_id {
+            MessageID::Choke => {
+                self.peer_choking = true;
+                // Requests in flight are dropped by a peer that chokes us
+                self.pipeline.choked();
+            },
+            MessageID::UnChoke => self.peer_choking = false,
+            _ => {}
+        }
+        if let Some(peer_pieces) = &mut self.peer_pieces {
+            match peer_message.message_id {
+                MessageID::Bitfield => {
+                    let payload = peer_message.payload.as_deref().unwrap_or_default();
+                    *peer_pieces = Bitfield::from_bytes(payload, peer_pieces.piece_count())
+                        .ok_or_else(|| format!("Peer {} sent an invalid bitfield", self.connection.peer_address))?;
+                    self.new_pieces.extend(peer_pieces.pieces());
+                },
+                MessageID::Have => {
+                    let payload = peer_message.payload.as_deref().unwrap_or_default();
+                    let piece_index = payload.get(..4).ok_or_else(|| format!("Peer {} sent an invalid have", self.connection.peer_address))?;
+                    let piece_index = u32::from_be_bytes(piece_index.try_into().unwrap()) as usize;
+                    if piece_index < peer_pieces.piece_count() && !peer_pieces.has(piece_index) {
+                        peer_pieces.set(piece_index);
+                        self.new_pieces.push(piece_index);
+                    }
+                },
+                _ => {}
+            }
+        }
+        if peer_message.message_id == MessageID::Extended {
+            for reply in self.extensions.handle(&peer_message)? {
+                self.connection.send(reply).await?;
+            }
+        } else if let Some(uploader) = &mut self.uploader {
+            uploader.handle(&peer_message)?;
+        }
+        Ok(peer_message)
+    }
+
+    // Queue a piece to download from this peer, its blocks are requested by `receive_blocks`. Blocks already
+    // `received` from someone else, or in `blocks` from the other peers downloading the piece, are not asked for again.
+    pub fn add_piece(&mut self, blocks : SharedPiece, received : Vec<PartialBlock>) {
+        self.pipeline.add_piece(blocks, received);
+    }
+
+    // True while the request window could use another piece
+    pub fn wants_piece(&self) -> bool {
+        self.pipeline.wants_piece()
+    }
+
+    // Pieces queued on this peer and not received yet
+    pub fn queued_pieces(&self) -> Vec<usize> {
+        self.pipeline.pieces()
+    }
+
+    // What we got of the queued pieces, to be picked up by another peer or on the next run
+    pub fn partial_pieces(&self) -> Vec<(usize, Vec<PartialBlock>)> {
+        self.pipeline.partial_pieces()
+    }
+
+    // Stop downloading a piece we got from someone else, the requests still in flight are cancelled
+    pub async fn cancel_piece(&mut self, piece_index : usize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for request in self.pipeline.cancel(piece_index) {
+            self.send_cancel(request).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn download_piece(&mut self, piece_index : usize, piece_length : u64) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut blocks = PieceBlocks::new(piece_index, piece_length);
+        blocks.downloaders = 1;
+        self.add_piece(Arc::new(Mutex::new(blocks)), vec![]);
+        loop {
+            if let Some(piece) = self.receive_blocks().await? {
+                return Ok(piece.data);
+            }
+        }
+    }
+
+    // Top up the requests in flight and handle the next message, returns a piece once all its blocks arrived
+    pub async fn receive_blocks(&mut self) -> Result<Option<DownloadedPiece>, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.am_interested && !self.pipeline.is_empty() {
+            self.connection.send(PeerMessage::new(MessageID::Interested, None)?).await?;
+            self.am_interested = true;
+            if let Some(uploader) = &self.uploader {
+                uploader.peer().set_am_interested(true);
+            }
+        }
+        if !self.peer_choking {
+            self.send_block_requests().await?;
+        }
+
+        // Other peers may have sent every block we could ask this one for, come back soon for something else
+        let idle = self.is_idle();
+        let peer_message = match self.next_message(Some(if idle { IDLE_POLL_INTERVAL } else { PEER_TIMEOUT })).await? {
+            Some(peer_message) => peer_message,
+            None if idle || self.is_idle() => return Ok(None),
+            None => return Err(format!("Peer {} timed out", self.connection.peer_address).into())
+        };
+        if peer_message.message_id != MessageID::Piece {
+            return Ok(None);
+        }
+        let payload = peer_message.payload.unwrap_or_default();
+        let piece = PieceDecoder::new().decode(&mut BytesMut::from(payload.as_slice()))?
+            .ok_or_else(|| format!("Peer {} sent an invalid piece", self.connection.peer_address))?;
+        let (piece_index, begin) = (piece.index as usize, piece.begin as u64);
+        let shared = self.pipeline.is_shared(piece_index);
+        if let Some(uploader) = &self.uploader {
+            uploader.peer().record_block(piece.block.len() as u64);
+        }
+        let downloaded = self.pipeline.received(piece);
+        // The other peers downloading the piece cancel their request for the block
+        if let Some(uploader) = self.uploader.as_ref().filter(|_| shared) {
+            uploader.store().block_arrived(piece_index, begin);
+        }
+        Ok(downloaded)
+    }
+
+    // Unchoked with nothing in flight
+    fn is_idle(&self) -> bool {
+        !self.peer_choking && self.pipeline.is_idle()
+    }
+
+    async fn send_block_requests(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.pipeline.set_peer_queue_depth(self.extensions.peer_handshake().and_then(|handshake| handshake.reqq));
+        for request in self.pipeline.next_requests() {
+            let mut request_bytes : BytesMut = BytesMut::new();
+            RequestEncoder::new().encode(request, &mut request_bytes)?;
+            self.connection.send(PeerMessage::new(MessageID::Request, Some(request_bytes.to_vec()))?).await?;
+        }
+        Ok(())
+    }
+}
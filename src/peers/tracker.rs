@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::LazyLock;
+use std::time::Duration;
+use rand::seq::SliceRandom;
+use tokio::time::timeout;
+use crate::metainfo::TorrentMetaInfo;
+use crate::peers::{TrackerResponse, UdpTracker, QUICK_RETRANSMISSIONS};
+
+// HTTP trackers that take longer than this to answer are given up on
+const HTTP_TIMEOUT : Duration = Duration::from_secs(30);
+// Longest we wait on a tracker before failing over to the next one, enough for a quick UDP exchange
+const ANNOUNCE_TIMEOUT : Duration = Duration::from_secs(60);
+
+// Shared by every request to HTTP trackers
+pub static HTTP_CLIENT : LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder().timeout(HTTP_TIMEOUT).build().unwrap_or_default()
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerError {
+    // The tracker answered but refused the request, with a human readable reason
+    Failure(String),
+    // The answer could not be understood
+    InvalidResponse(String)
+}
+
+impl Display for TrackerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "Tracker failure: {reason}"),
+            TrackerError::InvalidResponse(message) => write!(f, "Invalid tracker response: {message}")
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped
+}
+
+// https://wiki.theory.org/BitTorrentSpecification#Tracker_Request_Parameters
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash : [u8; 20],
+    pub peer_id : [u8; 20],
+    pub port : u16,
+    pub uploaded : u64,
+    pub downloaded : u64,
+    pub left : u64,
+    pub compact : bool,
+    pub event : Option<AnnounceEvent>,
+    // Whatever the tracker gave us in a previous answer
+    pub tracker_id : Option<String>
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped"
+        }
+    }
+
+    // https://www.bittorrent.org/beps/bep_0015.html
+    pub fn udp_code(&self) -> u32 {
+        match self {
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3
+        }
+    }
+}
+
+impl AnnounceRequest {
+    pub fn url(&self, tracker_url : &str) -> String {
+        let urlencoded_info_hash = urlencoding::encode_binary(&self.info_hash).to_string();
+        let urlencoded_peer_id = urlencoding::encode_binary(&self.peer_id).to_string();
+        let urlencoded_compact = match self.compact {
+            true => "1".to_string(),
+            false => "0".to_string()
+        };
+        // Some trackers already put parameters in their announce url
+        let separator = if tracker_url.contains('?') { "&" } else { "?" };
+        let mut url = tracker_url.to_string() + separator + "info_hash=" + &urlencoded_info_hash + "&peer_id=" + &urlencoded_peer_id
+            + "&port=" + &self.port.to_string() + "&uploaded=" + &self.uploaded.to_string() + "&downloaded=" + &self.downloaded.to_string()
+            + "&left=" + &self.left.to_string() + "&compact=" + &urlencoded_compact;
+        if let Some(event) = self.event {
+            url = url + "&event=" + event.as_str();
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            url = url + "&trackerid=" + &urlencoding::encode(tracker_id);
+        }
+        url
+    }
+}
+
+// UDP trackers get a couple of tries, not the whole retransmission schedule: a tracker that doesn't answer
+// quickly is skipped for the next one
+pub async fn announce(tracker_url : &str, request : &AnnounceRequest) -> Result<TrackerResponse, Box<dyn std::error::Error + Send + Sync>> {
+    announce_with_retransmissions(tracker_url, request, QUICK_RETRANSMISSIONS).await
+}
+
+// `max_retransmissions` only matters for UDP trackers
+pub async fn announce_with_retransmissions(tracker_url : &str, request : &AnnounceRequest, max_retransmissions : u32)
+    -> Result<TrackerResponse, Box<dyn std::error::Error + Send + Sync>> {
+    if tracker_url.starts_with("udp://") {
+        return UdpTracker::connect(tracker_url).await?.with_max_retransmissions(max_retransmissions).announce(request).await;
+    }
+    let body = HTTP_CLIENT.get(request.url(tracker_url))
+        .send()
+        .await?
+        .bytes()
+        .await?;
+    Ok(TrackerResponse::from_bytes(&body)?)
+}
+
+// https://www.bittorrent.org/beps/bep_0012.html
+// Trackers are grouped in tiers tried in order, a tracker that answers moves to the front of its tier.
+#[derive(Clone)]
+pub struct TrackerManager {
+    tiers : Vec<Vec<String>>,
+    tracker_ids : HashMap<String, String>
+}
+
+impl TrackerManager {
+    pub fn new(metainfo : &TorrentMetaInfo) -> Self {
+        let tiers = match &metainfo.announce_list {
+            Some(announce_list) if announce_list.iter().any(|tier| !tier.is_empty()) => announce_list.clone(),
+            _ => vec![vec![metainfo.announce.clone()]]
+        };
+        Self::from_tiers(tiers)
+    }
+
+    pub fn from_tiers(tiers : Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = tiers.into_iter()
+            .map(|tier| tier.into_iter().filter(|tracker| !tracker.is_empty()).collect::<Vec<String>>())
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        TrackerManager {
+            tiers,
+            tracker_ids: HashMap::new()
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    // Returns the first successful answer along with the tracker that gave it
+    pub async fn announce(&mut self, request : &AnnounceRequest) -> Result<(String, TrackerResponse), Box<dyn std::error::Error + Send + Sync>> {
+        let mut last_error : Box<dyn std::error::Error + Send + Sync> = "No tracker to announce to".into();
+        let mut request = request.clone();
+        // Even a lone tracker is only waited on for so long, the announcer tries it again later
+        for tier in self.tiers.iter_mut() {
+            for index in 0..tier.len() {
+                request.tracker_id = self.tracker_ids.get(&tier[index]).cloned();
+                let result = timeout(ANNOUNCE_TIMEOUT, announce(&tier[index], &request)).await
+                    .unwrap_or_else(|_| Err(format!("Tracker {} did not answer in time", tier[index]).into()));
+                match result {
+                    Ok(tracker_response) => {
+                        if let Some(tracker_id) = tracker_response.tracker_id() {
+                            self.tracker_ids.insert(tier[index].clone(), tracker_id.clone());
+                        }
+                        let tracker = tier.remove(index);
+                        tier.insert(0, tracker.clone());
+                        return Ok((tracker, tracker_response));
+                    },
+                    Err(err) => last_error = err
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
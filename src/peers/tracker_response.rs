@@ -1,22 +1,59 @@
 use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
+use crate::peers::TrackerError;
+
+// Used when a tracker does not say how often it wants to hear from us
+pub const DEFAULT_ANNOUNCE_INTERVAL : u64 = 30 * 60;
+
+// A compact peer is its address followed by its port, both in network byte order
+pub const COMPACT_PEER_V4_LENGTH : usize = 6;
+pub const COMPACT_PEER_V6_LENGTH : usize = 18;
+
+// Trackers send peers either as a compact byte string (https://www.bittorrent.org/beps/bep_0023.html)
+// or as a list of dictionaries
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum PeerList {
+    Compact(ByteBuf),
+    Dictionary(Vec<PeerEntry>)
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct PeerEntry {
+    #[serde(rename = "peer id")]
+    pub peer_id : Option<ByteBuf>,
+    pub ip : String,
+    pub port : u16
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(ByteBuf::new())
+    }
+}
+
 // https://wiki.theory.org/BitTorrentSpecification#Tracker_Response
+// A failure reason is all a tracker has to send, everything else is optional.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TrackerResponse {
     #[serde(rename = "failure reason")]
     failure_reason : Option<String>,
-    #[serde(rename = "warning reason")]
+    #[serde(rename = "warning message")]
     warning_message : Option<String>,
-    interval : u64,
+    interval : Option<u64>,
     #[serde(rename = "min interval")]
     min_interval : Option<u64>,
     #[serde(rename = "tracker id")]
     tracker_id : Option<String>,
-    complete : u64,
-    incomplete : u64,
-    peers : ByteBuf
+    complete : Option<u64>,
+    incomplete : Option<u64>,
+    #[serde(default)]
+    peers : PeerList,
+    // https://www.bittorrent.org/beps/bep_0007.html
+    peers6 : Option<ByteBuf>
 }
 
 impl Display for TrackerResponse {
@@ -30,7 +67,7 @@ impl Display for TrackerResponse {
             None => writeln!(f, "Warning message: None", )?,
             Some(message) => writeln!(f, "Warning message: {}", message)?,
         }
-        writeln!(f, "Interval: {}", self.interval)?;
+        writeln!(f, "Interval: {}", self.interval())?;
         match &self.min_interval {
             None => writeln!(f, "Min interval: None", )?,
             Some(min_interval) => writeln!(f, "Min interval: {}", min_interval)?,
@@ -39,8 +76,14 @@ impl Display for TrackerResponse {
             None => writeln!(f, "Tracker id: None", )?,
             Some(tracker_id) => writeln!(f, "Tracker id: {}", tracker_id)?,
         }
-        writeln!(f, "Complete: {}", self.complete)?;
-        writeln!(f, "Incomplete: {}", self.incomplete)?;
+        match &self.complete {
+            None => writeln!(f, "Complete: None", )?,
+            Some(complete) => writeln!(f, "Complete: {}", complete)?,
+        }
+        match &self.incomplete {
+            None => writeln!(f, "Incomplete: None", )?,
+            Some(incomplete) => writeln!(f, "Incomplete: {}", incomplete)?,
+        }
         let mut it = peers.iter().peekable();
         writeln!(f, "Peers:")?;
         while let Some(peer_ip) = it.next() {
@@ -55,31 +98,156 @@ impl Display for TrackerResponse {
 }
 
 impl TrackerResponse {
-    pub fn peers(&self) -> Vec<String> {
-        let mut i = 0;
-        let mut peers_ips : Vec<String> = vec![];
-        while i < self.peers.len() {
-            let ip_as_string = Self::ip_bytes_to_ip_string(&self.peers[i..i+6].to_vec());
-            peers_ips.push(ip_as_string);
-            i += 6;
+    // For transports that do not speak bencode, such as UDP trackers
+    pub fn new(interval : u64, complete : u64, incomplete : u64, peers : Vec<u8>, peers6 : Vec<u8>) -> Self {
+        TrackerResponse {
+            failure_reason: None,
+            warning_message: None,
+            interval: Some(interval),
+            min_interval: None,
+            tracker_id: None,
+            complete: Some(complete),
+            incomplete: Some(incomplete),
+            peers: PeerList::Compact(ByteBuf::from(peers)),
+            peers6: (!peers6.is_empty()).then(|| ByteBuf::from(peers6))
+        }
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self, TrackerError> {
+        let tracker_response : TrackerResponse = serde_bencode::from_bytes(bytes)
+            .map_err(|err| TrackerError::InvalidResponse(err.to_string()))?;
+        tracker_response.into_result()
+    }
+
+    // A tracker that refused the announce gives nothing else worth reading
+    pub fn into_result(self) -> Result<Self, TrackerError> {
+        match self.failure_reason {
+            Some(reason) => Err(TrackerError::Failure(reason)),
+            None => Ok(self)
+        }
+    }
+
+    pub fn warning(&self) -> Option<&String> {
+        self.warning_message.as_ref()
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(DEFAULT_ANNOUNCE_INTERVAL)
+    }
+
+    pub fn min_interval(&self) -> Option<u64> {
+        self.min_interval
+    }
+
+    pub fn tracker_id(&self) -> Option<&String> {
+        self.tracker_id.as_ref()
+    }
+
+    pub fn complete(&self) -> Option<u64> {
+        self.complete
+    }
+
+    pub fn incomplete(&self) -> Option<u64> {
+        self.incomplete
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers_ips : Vec<SocketAddr> = match &self.peers {
+            PeerList::Compact(peers) => peers.chunks_exact(COMPACT_PEER_V4_LENGTH).filter_map(Self::compact_peer).collect(),
+            // Peers given by host name are left alone, we only dial addresses
+            PeerList::Dictionary(peers) => peers.iter()
+                .filter_map(|peer| Some(SocketAddr::new(peer.ip.parse::<IpAddr>().ok()?, peer.port)))
+                .collect()
+        };
+        if let Some(peers6) = &self.peers6 {
+            peers_ips.extend(peers6.chunks_exact(COMPACT_PEER_V6_LENGTH).filter_map(Self::compact_peer));
         }
         peers_ips
     }
 
-    pub fn ip_bytes_to_ip_string(bytes : &Vec<u8>) -> String {
-        assert!(bytes.len() >= 6);
-        let mut ip_string = String::from("");
-        for i in 0..4 {
-            let byte = bytes[i];
-            ip_string.push_str(&*byte.to_string());
-            if i < 3 {
-                ip_string.push('.');
-            } else {
-                ip_string.push(':');
-            }
+    // Decode a compact peer, the address length tells IPv4 and IPv6 apart. None for any other length.
+    pub fn compact_peer(bytes : &[u8]) -> Option<SocketAddr> {
+        let (address, port) = bytes.split_at_checked(bytes.len().checked_sub(2)?)?;
+        let ip = match address.len() {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(address).ok()?)),
+            16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(address).ok()?)),
+            _ => return None
+        };
+        Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_reason_is_an_error() {
+        for (bytes, reason) in [
+            (&b"d14:failure reason15:unknown torrente"[..], "unknown torrent"),
+            // Whatever else comes with it is not worth reading
+            (b"d14:failure reason6:denied8:intervali60e5:peers0:e", "denied")
+        ] {
+            assert_eq!(TrackerResponse::from_bytes(bytes), Err(TrackerError::Failure(reason.to_string())));
+        }
+    }
+
+    #[test]
+    fn missing_fields_have_defaults() {
+        for (bytes, interval, min_interval, complete, warning) in [
+            (&b"de"[..], DEFAULT_ANNOUNCE_INTERVAL, None, None, None),
+            (b"d8:intervali900e5:peers0:e", 900, None, None, None),
+            (b"d8:completei4e8:intervali60e12:min intervali30e15:warning message4:slowe", 60, Some(30), Some(4), Some("slow"))
+        ] {
+            let response = TrackerResponse::from_bytes(bytes).unwrap();
+            assert_eq!(response.interval(), interval);
+            assert_eq!(response.min_interval(), min_interval);
+            assert_eq!(response.complete(), complete);
+            assert_eq!(response.incomplete(), None);
+            assert_eq!(response.warning().map(String::as_str), warning);
+            assert_eq!(response.tracker_id(), None);
+            assert!(response.peers().is_empty());
+        }
+    }
+
+    #[test]
+    fn invalid_bencode_is_an_invalid_response() {
+        for bytes in [&b""[..], b"d8:interval", b"le", b"d8:intervali1e5:peersi1ee"] {
+            assert!(matches!(TrackerResponse::from_bytes(bytes), Err(TrackerError::InvalidResponse(_))), "{}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn peer_lists() {
+        let v4 : SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let v6 : SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+        let v6_bytes = [&[0x20, 0x01, 0x0d, 0xb8][..], &[0; 11], &[1, 0x1a, 0xe2]].concat();
+        let compact = [&b"d5:peers6:"[..], &[127, 0, 0, 1, 0x1a, 0xe1], b"e"].concat();
+        // A trailing partial peer is dropped
+        let compact_partial = [&b"d5:peers8:"[..], &[127, 0, 0, 1, 0x1a, 0xe1, 10, 0], b"e"].concat();
+        let compact_and_peers6 = [&b"d5:peers6:"[..], &[127, 0, 0, 1, 0x1a, 0xe1], b"6:peers618:", &v6_bytes, b"e"].concat();
+        let only_peers6 = [&b"d6:peers618:"[..], &v6_bytes, b"e"].concat();
+        let dictionary = b"d5:peersld2:ip9:127.0.0.17:peer id20:-RB0100-abcdefghijkl4:porti6881eed2:ip11:2001:db8::14:porti6882eed2:ip12:peer.example4:porti6883eeee";
+        for (bytes, peers) in [
+            (&compact[..], vec![v4]),
+            (&compact_partial, vec![v4]),
+            (&compact_and_peers6, vec![v4, v6]),
+            (&only_peers6, vec![v6]),
+            // Peers given by host name are skipped
+            (&dictionary[..], vec![v4, v6]),
+            (b"d5:peerslee", vec![])
+        ] {
+            assert_eq!(TrackerResponse::from_bytes(bytes).unwrap().peers(), peers, "{}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn compact_peer_lengths() {
+        let v6_bytes = [&[0x20, 0x01, 0x0d, 0xb8][..], &[0; 11], &[1, 0x1a, 0xe2]].concat();
+        assert_eq!(TrackerResponse::compact_peer(&[127, 0, 0, 1, 0x1a, 0xe1]), Some("127.0.0.1:6881".parse().unwrap()));
+        assert_eq!(TrackerResponse::compact_peer(&v6_bytes), Some("[2001:db8::1]:6882".parse().unwrap()));
+        for length in [0, 1, 2, 5, 7, 17, 19] {
+            assert_eq!(TrackerResponse::compact_peer(&vec![1; length]), None, "{length} bytes");
         }
-        let port = u16::from_be_bytes([bytes[4], bytes[5]]);
-        ip_string.push_str(port.to_string().as_str());
-        ip_string
     }
 }
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout_at;
+
+use crate::peers::{AnnounceRequest, ScrapeFile, TrackerError, TrackerResponse};
+
+// https://www.bittorrent.org/beps/bep_0015.html
+const PROTOCOL_ID : u64 = 0x41727101980;
+const ACTION_CONNECT : u32 = 0;
+const ACTION_ANNOUNCE : u32 = 1;
+const ACTION_SCRAPE : u32 = 2;
+const ACTION_ERROR : u32 = 3;
+// A connection id can be reused for one minute after the tracker handed it out
+const CONNECTION_ID_LIFETIME : Duration = Duration::from_secs(60);
+// Requests are retransmitted after 15 * 2 ^ n seconds, n going from 0 up to 8
+pub const MAX_RETRANSMISSIONS : u32 = 8;
+// Someone is waiting for the answer, give up after 45 seconds instead of the two hours of the full schedule
+pub const QUICK_RETRANSMISSIONS : u32 = 1;
+// Trackers can answer with a scrape for at most this many info hashes
+pub const MAX_SCRAPE_INFO_HASHES : usize = 74;
+
+// Connection ids are shared by every request made to the same tracker
+static CONNECTION_IDS : LazyLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub struct UdpTracker {
+    socket : UdpSocket,
+    tracker_address : SocketAddr,
+    max_retransmissions : u32
+}
+
+impl UdpTracker {
+    pub async fn connect(tracker_url : &str) -> Result<Self, Error> {
+        let invalid = || Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid UDP tracker url: {tracker_url}"));
+        let url = reqwest::Url::parse(tracker_url).map_err(|_| invalid())?;
+        let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+            return Err(invalid());
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let tracker_address = tokio::net::lookup_host((host, port)).await?.next().ok_or_else(invalid)?;
+        let local_address = match tracker_address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+        let socket = UdpSocket::bind(local_address).await?;
+        socket.connect(tracker_address).await?;
+
+        Ok(UdpTracker {
+            socket,
+            tracker_address,
+            max_retransmissions: MAX_RETRANSMISSIONS
+        })
+    }
+
+    // Stop retransmitting after `max_retransmissions` instead of following the whole schedule
+    pub fn with_max_retransmissions(mut self, max_retransmissions : u32) -> Self {
+        self.max_retransmissions = max_retransmissions.min(MAX_RETRANSMISSIONS);
+        self
+    }
+
+    pub async fn announce(&self, request : &AnnounceRequest) -> Result<TrackerResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let key : u32 = rand::random();
+        let response = self.transact(ACTION_ANNOUNCE, 20, |connection_id, transaction_id| {
+            let mut packet : Vec<u8> = Vec::with_capacity(98);
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            packet.extend_from_slice(&request.info_hash);
+            packet.extend_from_slice(&request.peer_id);
+            packet.extend_from_slice(&request.downloaded.to_be_bytes());
+            packet.extend_from_slice(&request.left.to_be_bytes());
+            packet.extend_from_slice(&request.uploaded.to_be_bytes());
+            packet.extend_from_slice(&request.event.map_or(0, |event| event.udp_code()).to_be_bytes());
+            packet.extend_from_slice(&0u32.to_be_bytes()); // ip: let the tracker use the sender address
+            packet.extend_from_slice(&key.to_be_bytes());
+            packet.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: tracker default
+            packet.extend_from_slice(&request.port.to_be_bytes());
+            packet
+        }).await?;
+
+        let interval = read_u32(&response, 8);
+        let leechers = read_u32(&response, 12);
+        let seeders = read_u32(&response, 16);
+        // Peers come in the address family of the tracker we talked to
+        let peers = response[20..].to_vec();
+        Ok(match self.tracker_address {
+            SocketAddr::V4(_) => TrackerResponse::new(interval as u64, seeders as u64, leechers as u64, peers, vec![]),
+            SocketAddr::V6(_) => TrackerResponse::new(interval as u64, seeders as u64, leechers as u64, vec![], peers)
+        })
+    }
+
+    pub async fn scrape(&self, info_hashes : &[[u8; 20]]) -> Result<Vec<ScrapeFile>, Box<dyn std::error::Error + Send + Sync>> {
+        if info_hashes.is_empty() || info_hashes.len() > MAX_SCRAPE_INFO_HASHES {
+            return Err(format!("A UDP scrape takes between 1 and {MAX_SCRAPE_INFO_HASHES} info hashes").into());
+        }
+        let response = self.transact(ACTION_SCRAPE, 8 + 12 * info_hashes.len(), |connection_id, transaction_id| {
+            let mut packet : Vec<u8> = Vec::with_capacity(16 + 20 * info_hashes.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            for info_hash in info_hashes {
+                packet.extend_from_slice(info_hash);
+            }
+            packet
+        }).await?;
+
+        Ok((0..info_hashes.len()).map(|index| {
+            let offset = 8 + 12 * index;
+            ScrapeFile {
+                complete: read_u32(&response, offset) as u64,
+                downloaded: read_u32(&response, offset + 4) as u64,
+                incomplete: read_u32(&response, offset + 8) as u64,
+                name: None
+            }
+        }).collect())
+    }
+
+    // Send a request built from a valid connection id until the tracker answers, following the retransmission schedule
+    async fn transact<F>(&self, action : u32, minimum_length : usize, build_request : F) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>
+        where F: Fn(u64, u32) -> Vec<u8> {
+        for attempt in 0..=self.max_retransmissions {
+            let deadline = Instant::now() + Duration::from_secs(15 * 2u64.pow(attempt));
+            let connection_id = match self.cached_connection_id() {
+                Some(connection_id) => connection_id,
+                None => match self.request_connection_id(deadline).await? {
+                    Some(connection_id) => connection_id,
+                    None => continue
+                }
+            };
+            let transaction_id : u32 = rand::random();
+            self.socket.send(&build_request(connection_id, transaction_id)).await?;
+            if let Some(response) = self.receive(action, transaction_id, minimum_length, deadline).await? {
+                return Ok(response);
+            }
+        }
+        Err(format!("UDP tracker {} did not answer", self.tracker_address).into())
+    }
+
+    async fn request_connection_id(&self, deadline : Instant) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let transaction_id : u32 = rand::random();
+        let mut packet : Vec<u8> = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        self.socket.send(&packet).await?;
+
+        let Some(response) = self.receive(ACTION_CONNECT, transaction_id, 16, deadline).await? else {
+            return Ok(None);
+        };
+        let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+        CONNECTION_IDS.lock().unwrap().insert(self.tracker_address, (connection_id, Instant::now()));
+        Ok(Some(connection_id))
+    }
+
+    fn cached_connection_id(&self) -> Option<u64> {
+        let mut connection_ids = CONNECTION_IDS.lock().unwrap();
+        match connection_ids.get(&self.tracker_address) {
+            Some(&(connection_id, obtained)) if obtained.elapsed() < CONNECTION_ID_LIFETIME => Some(connection_id),
+            Some(_) => {
+                connection_ids.remove(&self.tracker_address);
+                None
+            },
+            None => None
+        }
+    }
+
+    // Wait for the answer to a transaction, returns None if the deadline passes first
+    async fn receive(&self, action : u32, transaction_id : u32, minimum_length : usize, deadline : Instant) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut buffer : Vec<u8> = vec![0; 64 * 1024];
+        loop {
+            let length = match timeout_at(deadline.into(), self.socket.recv(&mut buffer)).await {
+                Err(_) => return Ok(None),
+                Ok(length) => length?
+            };
+            // Late answers to earlier attempts are dropped
+            if length < 8 || read_u32(&buffer, 4) != transaction_id {
+                continue;
+            }
+            let response_action = read_u32(&buffer, 0);
+            if response_action == ACTION_ERROR {
+                return Err(TrackerError::Failure(String::from_utf8_lossy(&buffer[8..length]).to_string()).into());
+            }
+            if response_action != action || length < minimum_length {
+                return Err(TrackerError::InvalidResponse(format!("UDP tracker {} sent an unexpected answer", self.tracker_address)).into());
+            }
+            buffer.truncate(length);
+            return Ok(Some(buffer));
+        }
+    }
+}
+
+fn read_u32(bytes : &[u8], offset : usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::AnnounceEvent;
+
+    const CONNECTION_ID : u64 = 0x0123_4567_89ab_cdef;
+
+    // A tracker on a port of its own, so it gets its own connection id
+    async fn tracker() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        (socket, url)
+    }
+
+    // Wait for a request, check its header and return its transaction id, what follows the header and who sent it
+    async fn expect_request(tracker : &UdpSocket, connection_id : u64, action : u32) -> (u32, Vec<u8>, SocketAddr) {
+        let mut buffer : Vec<u8> = vec![0; 2048];
+        let (length, client) = tracker.recv_from(&mut buffer).await.unwrap();
+        assert!(length >= 16);
+        assert_eq!(u64::from_be_bytes(buffer[..8].try_into().unwrap()), connection_id);
+        assert_eq!(read_u32(&buffer, 8), action);
+        (read_u32(&buffer, 12), buffer[16..length].to_vec(), client)
+    }
+
+    async fn answer(tracker : &UdpSocket, client : SocketAddr, action : u32, transaction_id : u32, body : &[u8]) {
+        let packet = [&action.to_be_bytes()[..], &transaction_id.to_be_bytes(), body].concat();
+        tracker.send_to(&packet, client).await.unwrap();
+    }
+
+    async fn accept_connect(tracker : &UdpSocket) {
+        let (transaction_id, body, client) = expect_request(tracker, PROTOCOL_ID, ACTION_CONNECT).await;
+        assert!(body.is_empty());
+        answer(tracker, client, ACTION_CONNECT, transaction_id, &CONNECTION_ID.to_be_bytes()).await;
+    }
+
+    fn announce_request(event : Option<AnnounceEvent>) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 6881,
+            uploaded: 3,
+            downloaded: 4,
+            left: 5,
+            compact: true,
+            event,
+            tracker_id: None
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_round_trip() {
+        let (tracker, url) = tracker().await;
+        let client = UdpTracker::connect(&url).await.unwrap();
+        let peers : Vec<u8> = [[127, 0, 0, 1, 0x1a, 0xe1], [10, 0, 0, 2, 0x1a, 0xe2]].concat();
+        // The connection id of the first announce is reused by the next ones
+        for (index, (event, event_code)) in [(None, 0), (Some(AnnounceEvent::Started), 2), (Some(AnnounceEvent::Completed), 1), (Some(AnnounceEvent::Stopped), 3)].into_iter().enumerate() {
+            let request = announce_request(event);
+            let serve = async {
+                if index == 0 {
+                    accept_connect(&tracker).await;
+                }
+                let (transaction_id, body, client) = expect_request(&tracker, CONNECTION_ID, ACTION_ANNOUNCE).await;
+                assert_eq!(body.len(), 82);
+                assert_eq!(body[..20], [1; 20]);
+                assert_eq!(body[20..40], [2; 20]);
+                assert_eq!(body[40..48], 4u64.to_be_bytes());
+                assert_eq!(body[48..56], 5u64.to_be_bytes());
+                assert_eq!(body[56..64], 3u64.to_be_bytes());
+                assert_eq!(read_u32(&body, 64), event_code);
+                assert_eq!(read_u32(&body, 68), 0);
+                assert_eq!(body[76..80], (-1i32).to_be_bytes());
+                assert_eq!(body[80..82], 6881u16.to_be_bytes());
+                let body = [&1800u32.to_be_bytes()[..], &7u32.to_be_bytes(), &9u32.to_be_bytes(), &peers].concat();
+                answer(&tracker, client, ACTION_ANNOUNCE, transaction_id, &body).await;
+            };
+            let (response, _) = tokio::join!(client.announce(&request), serve);
+            let response = response.unwrap();
+            assert_eq!(response.interval(), 1800);
+            assert_eq!(response.complete(), Some(9));
+            assert_eq!(response.incomplete(), Some(7));
+            assert_eq!(response.peers(), ["127.0.0.1:6881".parse::<SocketAddr>().unwrap(), "10.0.0.2:6882".parse().unwrap()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape_round_trip() {
+        for count in [1, 3, MAX_SCRAPE_INFO_HASHES] {
+            let (tracker, url) = tracker().await;
+            let client = UdpTracker::connect(&url).await.unwrap();
+            let info_hashes : Vec<[u8; 20]> = (0..count).map(|index| [index as u8; 20]).collect();
+            let serve = async {
+                accept_connect(&tracker).await;
+                let (transaction_id, body, client) = expect_request(&tracker, CONNECTION_ID, ACTION_SCRAPE).await;
+                assert_eq!(body, info_hashes.concat());
+                let body : Vec<u8> = (0..count as u32).flat_map(|index| [index, index + 100, index + 200]).flat_map(u32::to_be_bytes).collect();
+                answer(&tracker, client, ACTION_SCRAPE, transaction_id, &body).await;
+            };
+            let (files, _) = tokio::join!(client.scrape(&info_hashes), serve);
+            let files = files.unwrap();
+            assert_eq!(files.len(), count);
+            for (index, file) in files.iter().enumerate() {
+                assert_eq!((file.complete, file.downloaded, file.incomplete), (index as u64, index as u64 + 100, index as u64 + 200));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape_rejects_info_hash_counts_out_of_range() {
+        let (_tracker, url) = tracker().await;
+        let client = UdpTracker::connect(&url).await.unwrap();
+        assert!(client.scrape(&[]).await.is_err());
+        assert!(client.scrape(&[[0; 20]; MAX_SCRAPE_INFO_HASHES + 1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_are_checked() {
+        // A failure reason for error answers, None for answers that make no sense
+        for (action, body, failure_reason) in [
+            (ACTION_ERROR, &b"torrent not registered"[..], Some("torrent not registered")),
+            (ACTION_SCRAPE, &[0; 12], None),
+            // Too short to hold the counts of an announce
+            (ACTION_ANNOUNCE, &[0; 8], None)
+        ] {
+            let (tracker, url) = tracker().await;
+            let client = UdpTracker::connect(&url).await.unwrap();
+            let request = announce_request(None);
+            let serve = async {
+                accept_connect(&tracker).await;
+                let (transaction_id, _, client) = expect_request(&tracker, CONNECTION_ID, ACTION_ANNOUNCE).await;
+                // A late answer to another transaction is dropped
+                answer(&tracker, client, ACTION_ANNOUNCE, transaction_id.wrapping_add(1), &[0; 12]).await;
+                answer(&tracker, client, action, transaction_id, body).await;
+            };
+            let (response, _) = tokio::join!(client.announce(&request), serve);
+            match (response.unwrap_err().downcast_ref::<TrackerError>().unwrap(), failure_reason) {
+                (TrackerError::Failure(reason), Some(failure_reason)) => assert_eq!(reason, failure_reason),
+                (TrackerError::InvalidResponse(_), None) => {},
+                (error, _) => panic!("Unexpected error {error:?}")
+            }
+        }
+    }
+}
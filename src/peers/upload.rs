@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Error;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::metainfo::{MerkleTree, Sha256Hash};
+use crate::peers::{ChokedPeer, HashRequest, Hashes, PieceStore, TransferStats, BLOCK_MAX};
+use crate::peers::extension::REQUEST_QUEUE_SIZE;
+use crate::peers::peer_message::{MessageID, PeerMessage};
+use crate::peers::piece::{Piece, PieceEncoder};
+use crate::peers::request::{Request, RequestDecoder};
+
+// Merkle trees of the files of a v2 torrent, by pieces root
+pub type MerkleTrees = HashMap<Sha256Hash, MerkleTree>;
+
+// The uploading side of a session: answers a peer's requests with blocks of the pieces we have,
+// whenever the choker lets us.
+pub struct Uploader {
+    store : Arc<PieceStore>,
+    peer : Arc<ChokedPeer>,
+    stats : Arc<TransferStats>,
+    new_pieces : broadcast::Receiver<usize>,
+    am_choking : bool,
+    requests : VecDeque<Request>,
+    merkle_trees : Arc<MerkleTrees>,
+    // Answers to hash requests, sent whether the peer is choked or not
+    hash_replies : Vec<PeerMessage>
+}
+
+impl Uploader {
+    pub fn new(store : Arc<PieceStore>, peer : Arc<ChokedPeer>, stats : Arc<TransferStats>) -> Self {
+        Uploader {
+            new_pieces: store.subscribe(),
+            store,
+            peer,
+            stats,
+            am_choking: true,
+            requests: VecDeque::new(),
+            merkle_trees: Arc::new(HashMap::new()),
+            hash_replies: vec![]
+        }
+    }
+
+    // Answer the hash requests of v2 peers from these trees, the others are rejected
+    pub fn with_merkle_trees(mut self, merkle_trees : Arc<MerkleTrees>) -> Self {
+        self.merkle_trees = merkle_trees;
+        self
+    }
+
+    pub fn store(&self) -> &Arc<PieceStore> {
+        &self.store
+    }
+
+    pub fn peer(&self) -> &Arc<ChokedPeer> {
+        &self.peer
+    }
+
+    // Has to be the first message after the handshake, peers assume we have nothing when it is missing
+    pub fn bitfield_message(&self) -> Result<Option<PeerMessage>, Error> {
+        let bitfield = self.store.bitfield();
+        if bitfield.is_empty() {
+            return Ok(None);
+        }
+        PeerMessage::new(MessageID::Bitfield, Some(bitfield.as_bytes().to_vec())).map(Some)
+    }
+
+    // Have for every piece written since the last call, a choke or unchoke when the choker changed
+    // its mind, and the answers to hash requests
+    pub fn pending_messages(&mut self) -> Result<Vec<PeerMessage>, Error> {
+        let mut messages : Vec<PeerMessage> = std::mem::take(&mut self.hash_replies);
+        loop {
+            match self.new_pieces.try_recv() {
+                Ok(piece_index) => messages.push(PeerMessage::new(MessageID::Have, Some((piece_index as u32).to_be_bytes().to_vec()))?),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break
+            }
+        }
+        if self.am_choking && self.peer.is_unchoked() {
+            self.am_choking = false;
+            messages.push(PeerMessage::new(MessageID::UnChoke, None)?);
+        } else if !self.am_choking && !self.peer.is_unchoked() {
+            self.am_choking = true;
+            // Requests are dropped on choke, the peer asks again once unchoked
+            self.requests.clear();
+            messages.push(PeerMessage::new(MessageID::Choke, None)?);
+        }
+        Ok(messages)
+    }
+
+    // Follow the peer's interest and requests, whatever goes back is sent by `pending_messages` and `next_block`
+    pub fn handle(&mut self, peer_message : &PeerMessage) -> Result<(), Error> {
+        match peer_message.message_id {
+            MessageID::Interested => self.peer.set_peer_interested(true),
+            MessageID::NotInterested => self.peer.set_peer_interested(false),
+            MessageID::Request => {
+                let request = Self::decode_request(peer_message)?;
+                // Requests sent while choked are dropped, the peer has to ask again once unchoked
+                if !self.am_choking && self.requests.len() < REQUEST_QUEUE_SIZE as usize && self.can_serve(&request) {
+                    self.requests.push_back(request);
+                }
+            },
+            MessageID::Cancel => {
+                let request = Self::decode_request(peer_message)?;
+                self.requests.retain(|queued| *queued != request);
+            },
+            MessageID::HashRequest => {
+                let request = HashRequest::from_bytes(peer_message.payload.as_deref().unwrap_or_default())?;
+                let hashes = self.merkle_trees.get(&request.pieces_root).and_then(|tree| {
+                    tree.hashes(request.base_layer, request.index as usize, request.length as usize, request.proof_layers)
+                });
+                let reply = match hashes {
+                    Some(hashes) => Hashes { request, hashes }.into_peer_message()?,
+                    None => request.reject()?
+                };
+                self.hash_replies.push(reply);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn has_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    // Read the oldest queued request from the store, None once everything has been served
+    pub fn next_block(&mut self) -> Result<Option<PeerMessage>, Error> {
+        let Some(request) = self.requests.pop_front() else {
+            return Ok(None);
+        };
+        let block = self.store.read_block(request.index() as usize, request.begin() as u64, request.length() as u64)?;
+        self.stats.add_uploaded(block.len() as u64);
+        self.peer.uploaded.add(block.len() as u64);
+        let mut payload : BytesMut = BytesMut::new();
+        PieceEncoder::new().encode(Piece::new(request.index(), request.begin(), block), &mut payload)?;
+        PeerMessage::new(MessageID::Piece, Some(payload.to_vec())).map(Some)
+    }
+
+    fn can_serve(&self, request : &Request) -> bool {
+        let piece_index = request.index() as usize;
+        request.length() > 0 && request.length() as u64 <= BLOCK_MAX && self.store.has_piece(piece_index)
+            && request.begin() as u64 + request.length() as u64 <= self.store.layout().piece_size(piece_index)
+    }
+
+    fn decode_request(peer_message : &PeerMessage) -> Result<Request, Error> {
+        let payload = peer_message.payload.as_deref().unwrap_or_default();
+        RequestDecoder::new().decode(&mut BytesMut::from(payload))?.ok_or_else(|| Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Request message is too short",
+        ))
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs;
+use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::metainfo::FileLayout;
+use crate::storage::{check_block, Storage};
+
+// The default backend: the files of the torrent under a download directory, as the other clients lay them out
+pub struct FileStorage {
+    layout : FileLayout,
+    directory : RwLock<PathBuf>
+}
+
+impl FileStorage {
+    // Create every file of the torrent at its final size, data already in them is kept
+    pub fn create(layout : FileLayout, directory : &Path) -> Result<Self, Error> {
+        for file_index in layout.data_files() {
+            let file_path = layout.full_path(directory, file_index);
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(file_path)?;
+            file.set_len(layout.files[file_index].length)?;
+        }
+        Ok(FileStorage {
+            layout,
+            directory: RwLock::new(directory.to_path_buf())
+        })
+    }
+
+    // Files already under `directory`, nothing is created: missing files fail to read
+    pub fn open(layout : FileLayout, directory : &Path) -> Self {
+        FileStorage {
+            layout,
+            directory: RwLock::new(directory.to_path_buf())
+        }
+    }
+
+    pub fn directory(&self) -> PathBuf {
+        self.directory.read().unwrap().clone()
+    }
+
+    pub fn file_path(&self, file_index : usize) -> PathBuf {
+        self.layout.full_path(&self.directory.read().unwrap(), file_index)
+    }
+
+    // Directories left empty by the files moving out, up to `directory`
+    fn remove_empty_directories(&self, directory : &Path) {
+        for file_index in self.layout.data_files() {
+            let mut parent = self.layout.full_path(directory, file_index).parent().map(Path::to_path_buf);
+            while let Some(path) = parent.filter(|path| path != directory) {
+                if fs::remove_dir(&path).is_err() {
+                    break;
+                }
+                parent = path.parent().map(Path::to_path_buf);
+            }
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece_index : usize, begin : u64, length : u64) -> Result<Vec<u8>, Error> {
+        check_block(&self.layout, piece_index, begin, length)?;
+        let mut block : Vec<u8> = vec![0; length as usize];
+        // Padding reads as the zeros the block starts with
+        for span in self.layout.spans(piece_index, begin, length).iter().filter(|span| !self.layout.files[span.file_index].padding) {
+            let mut file = fs::File::open(self.file_path(span.file_index))?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            file.read_exact(&mut block[span.data_offset as usize..(span.data_offset + span.length) as usize])?;
+        }
+        Ok(block)
+    }
+
+    fn write_block(&self, piece_index : usize, begin : u64, data : &[u8]) -> Result<(), Error> {
+        check_block(&self.layout, piece_index, begin, data.len() as u64)?;
+        for span in self.layout.spans(piece_index, begin, data.len() as u64).iter().filter(|span| !self.layout.files[span.file_index].padding) {
+            let mut file = fs::OpenOptions::new().write(true).open(self.file_path(span.file_index))?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            file.write_all(&data[span.data_offset as usize..(span.data_offset + span.length) as usize])?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        for file_index in self.layout.data_files() {
+            fs::OpenOptions::new().write(true).open(self.file_path(file_index))?.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Files are renamed when possible and copied over otherwise, to another file system for instance
+    fn move_to(&self, directory : &Path) -> Result<(), Error> {
+        let mut current = self.directory.write().unwrap();
+        if *current == directory {
+            return Ok(());
+        }
+        for file_index in self.layout.data_files() {
+            let from = self.layout.full_path(&current, file_index);
+            let to = self.layout.full_path(directory, file_index);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::rename(&from, &to).is_err() {
+                fs::copy(&from, &to)?;
+                fs::remove_file(&from)?;
+            }
+        }
+        let previous = std::mem::replace(&mut *current, directory.to_path_buf());
+        drop(current);
+        self.remove_empty_directories(&previous);
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        let directory = self.directory();
+        for file_index in self.layout.data_files() {
+            match fs::remove_file(self.layout.full_path(&directory, file_index)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        self.remove_empty_directories(&directory);
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::Error;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::metainfo::FileLayout;
+use crate::storage::{check_block, Storage};
+
+// The whole torrent in one buffer, for tests and for embedding where nothing should touch the disk.
+// Moving is a no-op and deleting drops the data.
+pub struct MemoryStorage {
+    layout : FileLayout,
+    data : RwLock<Vec<u8>>
+}
+
+impl MemoryStorage {
+    pub fn new(layout : FileLayout) -> Self {
+        MemoryStorage {
+            data: RwLock::new(vec![0; layout.total_length as usize]),
+            layout
+        }
+    }
+
+    // Everything stored so far, the files of the torrent concatenated
+    pub fn data(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+
+    fn offset(&self, piece_index : usize, begin : u64) -> usize {
+        (piece_index as u64 * self.layout.piece_length + begin) as usize
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece_index : usize, begin : u64, length : u64) -> Result<Vec<u8>, Error> {
+        check_block(&self.layout, piece_index, begin, length)?;
+        let offset = self.offset(piece_index, begin);
+        let data = self.data.read().unwrap();
+        data.get(offset..offset + length as usize).map(<[u8]>::to_vec).ok_or_else(|| Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("Block at {begin} of piece {piece_index} was deleted"),
+        ))
+    }
+
+    fn write_block(&self, piece_index : usize, begin : u64, block : &[u8]) -> Result<(), Error> {
+        check_block(&self.layout, piece_index, begin, block.len() as u64)?;
+        let offset = self.offset(piece_index, begin);
+        let mut data = self.data.write().unwrap();
+        // Writing after a delete starts over
+        data.resize(self.layout.total_length as usize, 0);
+        data[offset..offset + block.len()].copy_from_slice(block);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn move_to(&self, _directory : &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        *self.data.write().unwrap() = vec![];
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::sync::RwLock;
+use memmap2::MmapMut;
+
+use crate::metainfo::FileLayout;
+use crate::storage::{check_block, FileStorage, Storage};
+
+// The same files as FileStorage mapped in memory, the page cache does the buffering instead of
+// a read or write call per block. Meant for large torrents on 64-bit systems.
+pub struct MmapStorage {
+    files : FileStorage,
+    // None for empty files, they can't be mapped, and for padding
+    maps : RwLock<Vec<Option<MmapMut>>>
+}
+
+impl MmapStorage {
+    pub fn create(layout : FileLayout, directory : &Path) -> Result<Self, Error> {
+        let files = FileStorage::create(layout, directory)?;
+        let maps = Self::map(&files)?;
+        Ok(MmapStorage {
+            files,
+            maps: RwLock::new(maps)
+        })
+    }
+
+    fn map(files : &FileStorage) -> Result<Vec<Option<MmapMut>>, Error> {
+        let mut maps : Vec<Option<MmapMut>> = vec![];
+        for (file_index, entry) in files.layout().files.iter().enumerate() {
+            if entry.length == 0 || entry.padding {
+                maps.push(None);
+                continue;
+            }
+            let file = fs::OpenOptions::new().read(true).write(true).open(files.file_path(file_index))?;
+            // The files are ours, nobody else is supposed to truncate them while mapped
+            maps.push(Some(unsafe { MmapMut::map_mut(&file)? }));
+        }
+        Ok(maps)
+    }
+
+    // The files are gone, deleted or not mapped again after a failed move
+    fn unmapped(file_index : usize) -> Error {
+        Error::new(std::io::ErrorKind::NotFound, format!("File {file_index} of the torrent is not mapped"))
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &FileLayout {
+        self.files.layout()
+    }
+
+    fn read_block(&self, piece_index : usize, begin : u64, length : u64) -> Result<Vec<u8>, Error> {
+        check_block(self.layout(), piece_index, begin, length)?;
+        let maps = self.maps.read().unwrap();
+        let mut block : Vec<u8> = vec![0; length as usize];
+        for span in self.layout().spans(piece_index, begin, length) {
+            // Padding reads as zeros
+            let Some(map) = maps.get(span.file_index).ok_or_else(|| Self::unmapped(span.file_index))?.as_ref() else { continue };
+            block[span.data_offset as usize..(span.data_offset + span.length) as usize]
+                .copy_from_slice(&map[span.file_offset as usize..(span.file_offset + span.length) as usize]);
+        }
+        Ok(block)
+    }
+
+    fn write_block(&self, piece_index : usize, begin : u64, data : &[u8]) -> Result<(), Error> {
+        check_block(self.layout(), piece_index, begin, data.len() as u64)?;
+        let mut maps = self.maps.write().unwrap();
+        for span in self.layout().spans(piece_index, begin, data.len() as u64) {
+            let Some(map) = maps.get_mut(span.file_index).ok_or_else(|| Self::unmapped(span.file_index))?.as_mut() else { continue };
+            map[span.file_offset as usize..(span.file_offset + span.length) as usize]
+                .copy_from_slice(&data[span.data_offset as usize..(span.data_offset + span.length) as usize]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        for map in self.maps.read().unwrap().iter().flatten() {
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    fn move_to(&self, directory : &Path) -> Result<(), Error> {
+        let mut maps = self.maps.write().unwrap();
+        for map in maps.iter().flatten() {
+            map.flush()?;
+        }
+        maps.clear();
+        let moved = self.files.move_to(directory);
+        // Mapped again wherever the files ended up
+        *maps = Self::map(&self.files)?;
+        moved
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        let mut maps = self.maps.write().unwrap();
+        maps.clear();
+        self.files.delete()
+    }
+}
@@ -0,0 +1,48 @@
+mod file;
+mod mmap;
+mod memory;
+mod verify;
+
+use std::io::Error;
+use std::path::Path;
+pub use file::*;
+pub use mmap::*;
+pub use memory::*;
+pub use verify::*;
+
+use crate::metainfo::FileLayout;
+
+// Where the data of a torrent lives. Blocks are addressed by piece and offset in the piece, each backend
+// maps them to whatever it keeps the data in. Sessions read and write concurrently, hence `&self` everywhere.
+pub trait Storage : Send + Sync {
+    fn layout(&self) -> &FileLayout;
+
+    fn read_block(&self, piece_index : usize, begin : u64, length : u64) -> Result<Vec<u8>, Error>;
+
+    fn write_block(&self, piece_index : usize, begin : u64, data : &[u8]) -> Result<(), Error>;
+
+    // Hash of the piece as it is stored, to be compared with the one in the metainfo
+    fn hash_piece(&self, piece_index : usize) -> Result<Vec<u8>, Error> {
+        let piece = self.read_block(piece_index, 0, self.layout().piece_size(piece_index))?;
+        Ok(self.layout().piece_hash(piece_index, &piece))
+    }
+
+    // Make sure everything written so far survives a crash
+    fn flush(&self) -> Result<(), Error>;
+
+    // Move the data under `directory`, reads and writes go there afterwards
+    fn move_to(&self, directory : &Path) -> Result<(), Error>;
+
+    fn delete(&self) -> Result<(), Error>;
+}
+
+// Blocks have to stay inside their piece
+fn check_block(layout : &FileLayout, piece_index : usize, begin : u64, length : u64) -> Result<(), Error> {
+    if piece_index >= layout.piece_count() || begin + length > layout.piece_size(piece_index) {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Block at {begin} of {length} bytes is outside of piece {piece_index}"),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::peers::Bitfield;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Valid,
+    // Some data is there but it doesn't match the hash
+    Corrupt,
+    // Can't be read or was never written: only zeros
+    Missing
+}
+
+// How much of a file is covered by valid pieces
+#[derive(Debug, Clone)]
+pub struct FileCompletion {
+    pub path : PathBuf,
+    pub length : u64,
+    pub verified : u64
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub pieces : Vec<PieceStatus>,
+    pub files : Vec<FileCompletion>
+}
+
+impl VerifyReport {
+    // The valid pieces, what a download can start from
+    pub fn bitfield(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.pieces.len());
+        for piece_index in self.with_status(PieceStatus::Valid) {
+            bitfield.set(piece_index);
+        }
+        bitfield
+    }
+
+    pub fn with_status(&self, status : PieceStatus) -> Vec<usize> {
+        (0..self.pieces.len()).filter(|&piece_index| self.pieces[piece_index] == status).collect()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let corrupt = self.with_status(PieceStatus::Corrupt);
+        let missing = self.with_status(PieceStatus::Missing);
+        writeln!(f, "Pieces: {}/{} valid, {} corrupt, {} missing", self.pieces.len() - corrupt.len() - missing.len(),
+                 self.pieces.len(), corrupt.len(), missing.len())?;
+        for (label, pieces) in [("Corrupt", corrupt), ("Missing", missing)] {
+            if !pieces.is_empty() {
+                let pieces : Vec<String> = pieces.iter().map(usize::to_string).collect();
+                writeln!(f, "{label} pieces: {}", pieces.join(", "))?;
+            }
+        }
+        for file in &self.files {
+            let percent = if file.length == 0 { 100.0 } else { file.verified as f64 * 100.0 / file.length as f64 };
+            writeln!(f, "File '{}': {:.1}% ({}/{} bytes)", file.path.display(), percent, file.verified, file.length)?;
+        }
+        Ok(())
+    }
+}
+
+// Hash every piece of `storage` against `pieces_hash` (hex encoded hash of each piece), spread over all cores
+pub fn verify(storage : &dyn Storage, pieces_hash : &[String]) -> VerifyReport {
+    let pieces = for_each_piece(pieces_hash.len(), |piece_index| piece_status(storage, piece_index, &pieces_hash[piece_index]));
+
+    let layout = storage.layout();
+    let mut files : Vec<FileCompletion> = layout.files.iter()
+        .map(|file| FileCompletion { path: file.path.clone(), length: file.length, verified: 0 })
+        .collect();
+    for piece_index in (0..pieces.len()).filter(|&piece_index| pieces[piece_index] == PieceStatus::Valid) {
+        for span in layout.spans(piece_index, 0, layout.piece_size(piece_index)) {
+            files[span.file_index].verified += span.length;
+        }
+    }
+    let files = layout.data_files().map(|file_index| files[file_index].clone()).collect();
+    VerifyReport { pieces, files }
+}
+
+// Hash of every piece of `storage` in order, spread over all cores
+pub fn hash_pieces(storage : &dyn Storage) -> Result<Vec<Vec<u8>>, Error> {
+    for_each_piece(storage.layout().piece_count(), |piece_index| storage.hash_piece(piece_index)).into_iter().collect()
+}
+
+// Run `check` on every piece index, as many at once as there are cores. Results are in piece order.
+fn for_each_piece<T : Send, F : Fn(usize) -> T + Sync>(piece_count : usize, check : F) -> Vec<T> {
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get()).min(piece_count.max(1));
+    let next_piece = AtomicUsize::new(0);
+    let mut results : Vec<Option<T>> = (0..piece_count).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers : Vec<_> = (0..threads).map(|_| scope.spawn(|| {
+            let mut checked : Vec<(usize, T)> = vec![];
+            loop {
+                let piece_index = next_piece.fetch_add(1, Ordering::Relaxed);
+                if piece_index >= piece_count {
+                    return checked;
+                }
+                checked.push((piece_index, check(piece_index)));
+            }
+        })).collect();
+        for worker in workers {
+            for (piece_index, result) in worker.join().unwrap() {
+                results[piece_index] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(|result| result.expect("Every piece is checked")).collect()
+}
+
+fn piece_status(storage : &dyn Storage, piece_index : usize, expected_hash : &str) -> PieceStatus {
+    let Ok(piece) = storage.read_block(piece_index, 0, storage.layout().piece_size(piece_index)) else {
+        return PieceStatus::Missing;
+    };
+    if base16ct::lower::encode_string(&storage.layout().piece_hash(piece_index, &piece)) == expected_hash {
+        PieceStatus::Valid
+    } else if piece.iter().all(|&byte| byte == 0) {
+        PieceStatus::Missing
+    } else {
+        PieceStatus::Corrupt
+    }
+}